@@ -0,0 +1,895 @@
+//! Binary cell configuration shared by qhyper and its tools.
+//!
+//! A blob is a fixed header followed by the memory regions, the irqs and the devices, all
+//! little endian:
+//!
+//! | offset | field                                         |
+//! |--------|-----------------------------------------------|
+//! | 0x00   | magic `"QHYPCELL"`                            |
+//! | 0x08   | version                                       |
+//! | 0x0c   | crc32 of the blob with this field zeroed      |
+//! | 0x10   | total size                                    |
+//! | 0x14   | memory region, irq and device counts (3 u32)  |
+//! | 0x20   | name, NUL padded to 32 bytes                  |
+//! | 0x40   | cpu set, entry, dtb (3 u64)                   |
+//! | 0x58   | console: kind, flags (2 u32), address, size   |
+//! | 0x70   | SMCCC owners forwarded to the firmware (u64)  |
+//! | 0x78   | memory regions, 32 bytes each                 |
+//! |        | irqs, 4 bytes each, padded to 8               |
+//! |        | devices, 32 bytes each                        |
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Display, Formatter};
+
+use bitflags::bitflags;
+
+pub const MAGIC: [u8; 8] = *b"QHYPCELL";
+pub const VERSION: u32 = 3;
+pub const NAME_MAX: usize = 32;
+pub const PAGE_SIZE: u64 = 0x1000;
+/// The irqs a cell may own, the GIC SPIs.
+pub const SPI_RANGE: core::ops::Range<u32> = 32..1020;
+
+const HEADER_SIZE: usize = 0x78;
+const MEMORY_REGION_SIZE: usize = 32;
+const IRQ_SIZE: usize = 4;
+const DEVICE_SIZE: usize = 32;
+const CHECKSUM_OFFSET: usize = 0x0c;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MemFlags: u64 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+        /// Device memory.
+        const IO = 1 << 3;
+        /// The root cell may write images here until the cell starts.
+        const LOADABLE = 1 << 4;
+        /// Stays mapped in the root cell, e.g. buffers shared with a backend there.
+        const ROOT_SHARED = 1 << 5;
+        /// Backs a disk the hypervisor emulates for the cell, which does not map it.
+        const DISK = 1 << 6;
+    }
+}
+
+bitflags! {
+    /// SMCCC owners whose calls the cell may make to the firmware, bit `n` is owner `n`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct SmcOwners: u64 {
+        const CPU = 1 << 1;
+        /// Silicon provider services.
+        const SIP = 1 << 2;
+        const OEM = 1 << 3;
+        /// Standard secure services other than PSCI, which the hypervisor emulates.
+        const STANDARD_SECURE = 1 << 4;
+        const VENDOR_EL3 = 1 << 7;
+        /// Owners 48 and 49.
+        const TRUSTED_APP = 0b11 << 48;
+        /// Owners 50 to 63, e.g. OP-TEE.
+        const TRUSTED_OS = 0x3fff << 50;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub phys_start: u64,
+    /// Where the cell sees the region (IPA).
+    pub virt_start: u64,
+    pub size: u64,
+    pub flags: MemFlags,
+}
+
+impl MemoryRegion {
+    /// Only meaningful once [`CellConfig::validate`] checked the region does not wrap.
+    pub fn phys_end(&self) -> u64 {
+        self.phys_start + self.size
+    }
+
+    pub fn virt_end(&self) -> u64 {
+        self.virt_start + self.size
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleKind {
+    None = 0,
+    Pl011 = 1,
+    AuxMini = 2,
+}
+
+impl TryFrom<u32> for ConsoleKind {
+    type Error = ConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::None,
+            1 => Self::Pl011,
+            2 => Self::AuxMini,
+            v => return Err(ConfigError::BadConsole(v)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Console {
+    pub kind: ConsoleKind,
+    pub flags: u32,
+    pub address: u64,
+    pub size: u64,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self {
+            kind: ConsoleKind::None,
+            flags: 0,
+            address: 0,
+            size: 0,
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// `address` and `size` are a physical MMIO window, mapped 1:1.
+    Mmio = 1,
+    /// `address` is the bus/device/function, `size` is unused.
+    Pci = 2,
+    /// `address` and `size` are a virtio-mmio window in the cell, served by the backend of
+    /// the root cell.
+    Virtio = 3,
+    /// `address` and `size` are a virtio-mmio window in the cell, a console emulated by the
+    /// hypervisor on its UART. `flags` holds the SPI it raises.
+    VirtioConsole = 4,
+    /// `address` and `size` are a virtio-mmio window in the cell, a disk emulated by the
+    /// hypervisor on a [`MemFlags::DISK`] region. `flags` holds the SPI it raises, the index
+    /// of the region and [`DEVICE_READ_ONLY`].
+    VirtioBlk = 5,
+    /// `address` and `size` are a virtio-mmio window in the cell, a network card on the
+    /// switch of the hypervisor. `flags` holds the SPI it raises and the rate limit, `param`
+    /// the MAC address.
+    VirtioNet = 6,
+}
+
+impl DeviceKind {
+    /// Devices the hypervisor emulates itself, they raise the SPI in the low bits of `flags`.
+    pub fn is_emulated(self) -> bool {
+        matches!(
+            self,
+            Self::VirtioConsole | Self::VirtioBlk | Self::VirtioNet
+        )
+    }
+}
+
+impl TryFrom<u32> for DeviceKind {
+    type Error = ConfigError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => Self::Mmio,
+            2 => Self::Pci,
+            3 => Self::Virtio,
+            4 => Self::VirtioConsole,
+            5 => Self::VirtioBlk,
+            6 => Self::VirtioNet,
+            v => return Err(ConfigError::BadDevice(v)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub flags: u32,
+    pub address: u64,
+    pub size: u64,
+    /// Depends on the kind, the MAC address of a network card in the low 48 bits, first byte
+    /// highest.
+    pub param: u64,
+}
+
+/// Bits of [`Device::flags`] holding the SPI of an emulated device.
+pub const DEVICE_IRQ_MASK: u32 = 0x3ff;
+/// Bits of [`Device::flags`] holding the index of the memory region backing a disk.
+pub const DEVICE_REGION_MASK: u32 = 0xff << DEVICE_REGION_SHIFT;
+pub const DEVICE_REGION_SHIFT: u32 = 16;
+/// The guest may not write to the disk.
+pub const DEVICE_READ_ONLY: u32 = 1 << 31;
+/// Bits of [`Device::flags`] holding the rate limit of a network card in Mbit/s, 0 if none.
+pub const DEVICE_RATE_MASK: u32 = 0xffff << DEVICE_RATE_SHIFT;
+pub const DEVICE_RATE_SHIFT: u32 = 16;
+
+impl Device {
+    /// The SPI raised by an emulated device, the cell must own it.
+    pub fn irq(&self) -> u32 {
+        self.flags & DEVICE_IRQ_MASK
+    }
+
+    /// The memory region backing a disk.
+    pub fn region(&self) -> usize {
+        ((self.flags & DEVICE_REGION_MASK) >> DEVICE_REGION_SHIFT) as usize
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.flags & DEVICE_READ_ONLY != 0
+    }
+
+    /// The rate limit of a network card in Mbit/s, 0 if none.
+    pub fn rate(&self) -> u32 {
+        (self.flags & DEVICE_RATE_MASK) >> DEVICE_RATE_SHIFT
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        let bytes = self.param.to_be_bytes();
+        [bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]
+    }
+}
+
+/// Everything a cell owns.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CellConfig {
+    pub name: String,
+    /// Bit `n` set if logical cpu `n` belongs to the cell, the lowest one boots it.
+    pub cpu_set: u64,
+    /// Where the boot cpu starts, in IPA.
+    pub entry: u64,
+    /// The dtb passed in `x0`, in IPA, 0 if none. The cell sees its `n`th cpu, lowest first,
+    /// with affinity `n / 16` in Aff1 and `n % 16` in Aff0, whatever cpu it runs on.
+    pub dtb: u64,
+    pub console: Console,
+    pub smc_owners: SmcOwners,
+    pub memory_regions: Vec<MemoryRegion>,
+    pub irqs: Vec<u32>,
+    pub devices: Vec<Device>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    Truncated,
+    BadMagic,
+    BadVersion(u32),
+    BadChecksum {
+        expected: u32,
+        found: u32,
+    },
+    BadSize {
+        header: usize,
+        actual: usize,
+    },
+    BadName,
+    BadConsole(u32),
+    BadDevice(u32),
+    NoCpu,
+    /// Irq `n` is not an SPI.
+    BadIrq(u32),
+    /// Memory region `n` is empty or not page aligned.
+    Unaligned(usize),
+    /// Memory regions `a` and `b` of the cell overlap.
+    Overlap(usize, usize),
+    /// Memory region `n` ends past the address space.
+    RegionWraps(usize),
+    /// The window of device `n` ends past the address space.
+    DeviceWraps(usize),
+    /// The console window ends past the address space.
+    ConsoleWraps,
+    /// Another cell already owns cpu `n`.
+    CpuBusy(usize),
+    /// Another cell already owns irq `n`.
+    IrqBusy(u32),
+    /// Memory region `n` overlaps memory owned by someone else.
+    MemoryBusy(usize),
+    /// The window of device `n` overlaps memory or a device owned by someone else.
+    DeviceBusy(usize),
+    /// The console window overlaps memory or a device owned by someone else.
+    ConsoleBusy,
+    /// Device `n` raises an irq the cell does not own.
+    DeviceIrq(usize),
+    /// Disk `n` is not backed by a [`MemFlags::DISK`] region of the cell.
+    DeviceRegion(usize),
+    /// Network card `n` has no unicast MAC address.
+    DeviceMac(usize),
+    /// Another cell already has the MAC address of network card `n`.
+    MacBusy(usize),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "truncated config"),
+            Self::BadMagic => write!(f, "bad magic"),
+            Self::BadVersion(v) => write!(f, "unsupported version {}", v),
+            Self::BadChecksum { expected, found } => {
+                write!(f, "checksum {:#010x}, expected {:#010x}", found, expected)
+            }
+            Self::BadSize { header, actual } => {
+                write!(f, "size {:#x} in header, blob is {:#x}", header, actual)
+            }
+            Self::BadName => write!(f, "name is not utf-8 or too long"),
+            Self::BadConsole(v) => write!(f, "unknown console kind {}", v),
+            Self::BadDevice(v) => write!(f, "unknown device kind {}", v),
+            Self::NoCpu => write!(f, "no cpu"),
+            Self::BadIrq(irq) => write!(f, "irq {} is not an SPI", irq),
+            Self::Unaligned(i) => write!(f, "memory region {} empty or not page aligned", i),
+            Self::Overlap(a, b) => write!(f, "memory regions {} and {} overlap", a, b),
+            Self::RegionWraps(i) => write!(f, "memory region {} wraps around", i),
+            Self::DeviceWraps(i) => write!(f, "device {} wraps around", i),
+            Self::ConsoleWraps => write!(f, "console wraps around"),
+            Self::CpuBusy(cpu) => write!(f, "cpu {} already assigned", cpu),
+            Self::IrqBusy(irq) => write!(f, "irq {} already assigned", irq),
+            Self::MemoryBusy(i) => write!(f, "memory region {} already in use", i),
+            Self::DeviceIrq(i) => write!(f, "device {} raises an irq not in the cell", i),
+            Self::DeviceRegion(i) => write!(f, "device {} has no disk region", i),
+            Self::DeviceMac(i) => write!(f, "device {} has no unicast mac address", i),
+            Self::DeviceBusy(i) => write!(f, "device {} already in use", i),
+            Self::ConsoleBusy => write!(f, "console already in use"),
+            Self::MacBusy(i) => write!(f, "mac address of device {} already in use", i),
+        }
+    }
+}
+
+impl CellConfig {
+    /// Parse and [`validate`](Self::validate) a blob.
+    pub fn parse(data: &[u8]) -> Result<Self, ConfigError> {
+        let mut r = Reader { data, pos: 0 };
+
+        if r.bytes(8)? != MAGIC {
+            return Err(ConfigError::BadMagic);
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(ConfigError::BadVersion(version));
+        }
+        let checksum = r.u32()?;
+        let total_size = r.u32()? as usize;
+        if total_size != data.len() {
+            return Err(ConfigError::BadSize {
+                header: total_size,
+                actual: data.len(),
+            });
+        }
+        let expected = checksum_of(data);
+        if checksum != expected {
+            return Err(ConfigError::BadChecksum {
+                expected,
+                found: checksum,
+            });
+        }
+
+        let num_regions = r.u32()? as usize;
+        let num_irqs = r.u32()? as usize;
+        let num_devices = r.u32()? as usize;
+        if body_size(num_regions, num_irqs, num_devices) != total_size {
+            return Err(ConfigError::BadSize {
+                header: total_size,
+                actual: body_size(num_regions, num_irqs, num_devices),
+            });
+        }
+
+        let name = r.bytes(NAME_MAX)?;
+        let name = name.split(|&b| b == 0).next().unwrap_or_default();
+        let name = String::from(core::str::from_utf8(name).map_err(|_| ConfigError::BadName)?);
+
+        let cpu_set = r.u64()?;
+        let entry = r.u64()?;
+        let dtb = r.u64()?;
+        let console = Console {
+            kind: r.u32()?.try_into()?,
+            flags: r.u32()?,
+            address: r.u64()?,
+            size: r.u64()?,
+        };
+        let smc_owners = SmcOwners::from_bits_retain(r.u64()?);
+
+        let mut memory_regions = Vec::with_capacity(num_regions);
+        for _ in 0..num_regions {
+            memory_regions.push(MemoryRegion {
+                phys_start: r.u64()?,
+                virt_start: r.u64()?,
+                size: r.u64()?,
+                flags: MemFlags::from_bits_retain(r.u64()?),
+            });
+        }
+        let mut irqs = Vec::with_capacity(num_irqs);
+        for _ in 0..num_irqs {
+            irqs.push(r.u32()?);
+        }
+        r.align8();
+        let mut devices = Vec::with_capacity(num_devices);
+        for _ in 0..num_devices {
+            devices.push(Device {
+                kind: r.u32()?.try_into()?,
+                flags: r.u32()?,
+                address: r.u64()?,
+                size: r.u64()?,
+                param: r.u64()?,
+            });
+        }
+
+        let config = Self {
+            name,
+            cpu_set,
+            entry,
+            dtb,
+            console,
+            smc_owners,
+            memory_regions,
+            irqs,
+            devices,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Serialize to a blob, the inverse of [`parse`](Self::parse).
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ConfigError> {
+        self.validate()?;
+        let total_size = body_size(
+            self.memory_regions.len(),
+            self.irqs.len(),
+            self.devices.len(),
+        );
+
+        let mut w = Vec::with_capacity(total_size);
+        w.extend_from_slice(&MAGIC);
+        w.extend_from_slice(&VERSION.to_le_bytes());
+        w.extend_from_slice(&0u32.to_le_bytes());
+        w.extend_from_slice(&(total_size as u32).to_le_bytes());
+        w.extend_from_slice(&(self.memory_regions.len() as u32).to_le_bytes());
+        w.extend_from_slice(&(self.irqs.len() as u32).to_le_bytes());
+        w.extend_from_slice(&(self.devices.len() as u32).to_le_bytes());
+
+        let mut name = [0u8; NAME_MAX];
+        name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        w.extend_from_slice(&name);
+
+        for v in [self.cpu_set, self.entry, self.dtb] {
+            w.extend_from_slice(&v.to_le_bytes());
+        }
+        w.extend_from_slice(&(self.console.kind as u32).to_le_bytes());
+        w.extend_from_slice(&self.console.flags.to_le_bytes());
+        w.extend_from_slice(&self.console.address.to_le_bytes());
+        w.extend_from_slice(&self.console.size.to_le_bytes());
+        w.extend_from_slice(&self.smc_owners.bits().to_le_bytes());
+
+        for region in &self.memory_regions {
+            for v in [
+                region.phys_start,
+                region.virt_start,
+                region.size,
+                region.flags.bits(),
+            ] {
+                w.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        for irq in &self.irqs {
+            w.extend_from_slice(&irq.to_le_bytes());
+        }
+        w.resize(w.len().next_multiple_of(8), 0);
+        for device in &self.devices {
+            w.extend_from_slice(&(device.kind as u32).to_le_bytes());
+            w.extend_from_slice(&device.flags.to_le_bytes());
+            w.extend_from_slice(&device.address.to_le_bytes());
+            w.extend_from_slice(&device.size.to_le_bytes());
+            w.extend_from_slice(&device.param.to_le_bytes());
+        }
+
+        let checksum = checksum_of(&w);
+        w[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        Ok(w)
+    }
+
+    /// Checks that only need the cell itself: a name, a cpu, SPIs only, page aligned regions
+    /// that do not overlap each other, neither physically nor in the cell.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.name.is_empty() || self.name.len() > NAME_MAX || self.name.contains('\0') {
+            return Err(ConfigError::BadName);
+        }
+        if self.cpu_set == 0 {
+            return Err(ConfigError::NoCpu);
+        }
+        if let Some(&irq) = self.irqs.iter().find(|irq| !SPI_RANGE.contains(irq)) {
+            return Err(ConfigError::BadIrq(irq));
+        }
+        for (i, r) in self.memory_regions.iter().enumerate() {
+            if r.size == 0
+                || r.phys_start % PAGE_SIZE != 0
+                || r.virt_start % PAGE_SIZE != 0
+                || r.size % PAGE_SIZE != 0
+            {
+                return Err(ConfigError::Unaligned(i));
+            }
+            if r.phys_start.checked_add(r.size).is_none()
+                || r.virt_start.checked_add(r.size).is_none()
+            {
+                return Err(ConfigError::RegionWraps(i));
+            }
+            for (j, o) in self.memory_regions.iter().enumerate().take(i) {
+                if overlaps(r.phys_start, r.phys_end(), o.phys_start, o.phys_end())
+                    || overlaps(r.virt_start, r.virt_end(), o.virt_start, o.virt_end())
+                {
+                    return Err(ConfigError::Overlap(j, i));
+                }
+            }
+        }
+        // The windows are mapped as whole pages, the last one must fit too.
+        let wraps = |address: u64, size: u64| {
+            address
+                .checked_add(size)
+                .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+                .is_none()
+        };
+        if self.console.kind != ConsoleKind::None && wraps(self.console.address, self.console.size)
+        {
+            return Err(ConfigError::ConsoleWraps);
+        }
+        for (i, d) in self.devices.iter().enumerate() {
+            if d.kind != DeviceKind::Pci && wraps(d.address, d.size) {
+                return Err(ConfigError::DeviceWraps(i));
+            }
+            if d.kind.is_emulated() && !self.irqs.contains(&d.irq()) {
+                return Err(ConfigError::DeviceIrq(i));
+            }
+            let disk = self.memory_regions.get(d.region());
+            if d.kind == DeviceKind::VirtioBlk
+                && !disk.is_some_and(|r| r.flags.contains(MemFlags::DISK))
+            {
+                return Err(ConfigError::DeviceRegion(i));
+            }
+            if d.kind == DeviceKind::VirtioNet
+                && (d.param == 0 || d.param >> 48 != 0 || d.mac()[0] & 1 != 0)
+            {
+                return Err(ConfigError::DeviceMac(i));
+            }
+        }
+        Ok(())
+    }
+
+    /// The logical cpus of the cell, lowest first.
+    pub fn cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..64).filter(|cpu| self.cpu_set & (1 << cpu) != 0)
+    }
+
+    /// The pages of the MMIO device `n`, mapped 1:1 into the cell.
+    pub fn device_window(&self, n: usize) -> (u64, u64) {
+        let d = &self.devices[n];
+        page_window(d.address, d.size)
+    }
+
+    /// The pages of the console, mapped 1:1 into the cell, if it has one.
+    pub fn console_window(&self) -> Option<(u64, u64)> {
+        (self.console.kind != ConsoleKind::None)
+            .then(|| page_window(self.console.address, self.console.size))
+    }
+
+    /// Physical windows mapped 1:1 into the cell besides its memory regions.
+    fn mmio_windows(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        (0..self.devices.len())
+            .filter(|&i| self.devices[i].kind == DeviceKind::Mmio)
+            .map(|i| self.device_window(i))
+            .chain(self.console_window())
+    }
+
+    /// Whether the physical range `[start, end)` touches a region or an MMIO window of the
+    /// cell.
+    fn claims(&self, start: u64, end: u64) -> bool {
+        self.memory_regions
+            .iter()
+            .any(|r| overlaps(start, end, r.phys_start, r.phys_end()))
+            || self.mmio_windows().any(|(s, e)| overlaps(start, end, s, e))
+    }
+
+    /// Checks against a cell that already exists: no shared cpu or irq, no shared physical
+    /// memory or MMIO window, except regions both sides mark [`MemFlags::ROOT_SHARED`], and
+    /// no shared MAC address.
+    pub fn check_against(&self, other: &CellConfig) -> Result<(), ConfigError> {
+        if let Some(cpu) = self.cpus().find(|cpu| other.cpu_set & (1 << cpu) != 0) {
+            return Err(ConfigError::CpuBusy(cpu));
+        }
+        if let Some(&irq) = self.irqs.iter().find(|irq| other.irqs.contains(irq)) {
+            return Err(ConfigError::IrqBusy(irq));
+        }
+        for (i, r) in self.memory_regions.iter().enumerate() {
+            let busy = other.memory_regions.iter().any(|o| {
+                overlaps(r.phys_start, r.phys_end(), o.phys_start, o.phys_end())
+                    && !(r.flags & o.flags).contains(MemFlags::ROOT_SHARED)
+            }) || other
+                .mmio_windows()
+                .any(|(s, e)| overlaps(r.phys_start, r.phys_end(), s, e));
+            if busy {
+                return Err(ConfigError::MemoryBusy(i));
+            }
+        }
+        for (i, d) in self.devices.iter().enumerate() {
+            if d.kind == DeviceKind::Mmio {
+                let (start, end) = self.device_window(i);
+                if other.claims(start, end) {
+                    return Err(ConfigError::DeviceBusy(i));
+                }
+            }
+        }
+        if let Some((start, end)) = self.console_window() {
+            if other.claims(start, end) {
+                return Err(ConfigError::ConsoleBusy);
+            }
+        }
+        let is_net = |d: &&Device| d.kind == DeviceKind::VirtioNet;
+        for (i, d) in self.devices.iter().enumerate() {
+            if is_net(&d)
+                && other
+                    .devices
+                    .iter()
+                    .filter(is_net)
+                    .any(|o| o.param == d.param)
+            {
+                return Err(ConfigError::MacBusy(i));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn overlaps(a_start: u64, a_end: u64, b_start: u64, b_end: u64) -> bool {
+    a_start < b_end && b_start < a_end
+}
+
+/// `[address, address + size)` widened to whole pages.
+fn page_window(address: u64, size: u64) -> (u64, u64) {
+    (
+        address - address % PAGE_SIZE,
+        (address + size).next_multiple_of(PAGE_SIZE),
+    )
+}
+
+fn body_size(num_regions: usize, num_irqs: usize, num_devices: usize) -> usize {
+    HEADER_SIZE
+        + num_regions * MEMORY_REGION_SIZE
+        + (num_irqs * IRQ_SIZE).next_multiple_of(8)
+        + num_devices * DEVICE_SIZE
+}
+
+/// CRC-32 (IEEE) of `data` with the checksum field taken as zero.
+fn checksum_of(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for (i, &byte) in data.iter().enumerate() {
+        let byte = match i {
+            CHECKSUM_OFFSET..=0x0f => 0,
+            _ => byte,
+        };
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ConfigError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(ConfigError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, ConfigError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ConfigError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn align8(&mut self) {
+        self.pos = self.pos.next_multiple_of(8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn region(phys_start: u64, virt_start: u64, size: u64, flags: MemFlags) -> MemoryRegion {
+        MemoryRegion {
+            phys_start,
+            virt_start,
+            size,
+            flags,
+        }
+    }
+
+    fn cell() -> CellConfig {
+        CellConfig {
+            name: String::from("linux"),
+            cpu_set: 0b110,
+            entry: 0x4008_0000,
+            dtb: 0x4800_0000,
+            console: Console {
+                kind: ConsoleKind::Pl011,
+                flags: 0,
+                address: 0x0900_0000,
+                size: 0x1000,
+            },
+            smc_owners: SmcOwners::SIP,
+            memory_regions: vec![
+                region(
+                    0x5000_0000,
+                    0x4000_0000,
+                    0x1000_0000,
+                    MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE | MemFlags::LOADABLE,
+                ),
+                region(0x6000_0000, 0x6000_0000, 0x10_0000, MemFlags::DISK),
+            ],
+            irqs: vec![40, 41, 42],
+            devices: vec![
+                Device {
+                    kind: DeviceKind::Mmio,
+                    flags: 0,
+                    address: 0x0a00_0000,
+                    size: 0x200,
+                    param: 0,
+                },
+                Device {
+                    kind: DeviceKind::VirtioBlk,
+                    flags: 41 | 1 << DEVICE_REGION_SHIFT | DEVICE_READ_ONLY,
+                    address: 0x0a00_1000,
+                    size: 0x200,
+                    param: 0,
+                },
+                Device {
+                    kind: DeviceKind::VirtioNet,
+                    flags: 42 | 100 << DEVICE_RATE_SHIFT,
+                    address: 0x0a00_2000,
+                    size: 0x200,
+                    param: 0x5254_0012_3456,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = cell();
+        let blob = config.to_bytes().unwrap();
+        assert_eq!(blob.len() % 8, 0);
+        assert_eq!(CellConfig::parse(&blob), Ok(config));
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut blob = cell().to_bytes().unwrap();
+        blob[0x20] ^= 1;
+        assert!(matches!(
+            CellConfig::parse(&blob),
+            Err(ConfigError::BadChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let blob = cell().to_bytes().unwrap();
+        assert_eq!(CellConfig::parse(&blob[..6]), Err(ConfigError::Truncated));
+        assert_eq!(
+            CellConfig::parse(&blob[..blob.len() - 8]),
+            Err(ConfigError::BadSize {
+                header: blob.len(),
+                actual: blob.len() - 8,
+            })
+        );
+    }
+
+    #[test]
+    fn counts_do_not_match_size() {
+        let mut blob = cell().to_bytes().unwrap();
+        // Two irqs more need 8 bytes the blob does not have.
+        blob[0x18..0x1c].copy_from_slice(&5u32.to_le_bytes());
+        let checksum = checksum_of(&blob);
+        blob[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            CellConfig::parse(&blob),
+            Err(ConfigError::BadSize { .. })
+        ));
+    }
+
+    #[test]
+    fn overlap() {
+        let mut config = cell();
+        config.memory_regions[1].phys_start = 0x5fff_f000;
+        assert_eq!(config.validate(), Err(ConfigError::Overlap(0, 1)));
+
+        let mut config = cell();
+        config.memory_regions[1].virt_start = 0x4000_0000;
+        assert_eq!(config.validate(), Err(ConfigError::Overlap(0, 1)));
+    }
+
+    #[test]
+    fn wraps() {
+        let mut config = cell();
+        config.memory_regions[1].phys_start = u64::MAX - 0xfff;
+        config.memory_regions[1].size = 0x2000;
+        assert_eq!(config.validate(), Err(ConfigError::RegionWraps(1)));
+
+        let mut config = cell();
+        config.memory_regions[1].virt_start = u64::MAX - 0xfff;
+        assert_eq!(config.validate(), Err(ConfigError::RegionWraps(1)));
+
+        // The end fits, not its page.
+        let mut config = cell();
+        config.devices[0].address = u64::MAX - 0x100;
+        config.devices[0].size = 0x10;
+        assert_eq!(config.validate(), Err(ConfigError::DeviceWraps(0)));
+
+        let mut config = cell();
+        config.console.address = u64::MAX;
+        assert_eq!(config.validate(), Err(ConfigError::ConsoleWraps));
+    }
+
+    #[test]
+    fn irqs() {
+        let mut config = cell();
+        config.irqs.push(27);
+        assert_eq!(config.validate(), Err(ConfigError::BadIrq(27)));
+
+        let mut config = cell();
+        config.irqs.retain(|&irq| irq != 41);
+        assert_eq!(config.validate(), Err(ConfigError::DeviceIrq(1)));
+    }
+
+    #[test]
+    fn busy() {
+        let config = cell();
+        let mut other = cell();
+        other.name = String::from("other");
+        other.cpu_set = 0b1000;
+        other.irqs = vec![50];
+        other.devices.clear();
+        other.console.kind = ConsoleKind::None;
+        other.memory_regions = vec![region(
+            0x7000_0000,
+            0x4000_0000,
+            0x1000_0000,
+            MemFlags::READ | MemFlags::WRITE,
+        )];
+        assert_eq!(config.check_against(&other), Ok(()));
+
+        let mut taken = other.clone();
+        taken.cpu_set = 0b10;
+        assert_eq!(config.check_against(&taken), Err(ConfigError::CpuBusy(1)));
+
+        let mut taken = other.clone();
+        taken.irqs = vec![42];
+        assert_eq!(config.check_against(&taken), Err(ConfigError::IrqBusy(42)));
+
+        let mut taken = other.clone();
+        taken.memory_regions[0].phys_start = 0x5800_0000;
+        assert_eq!(
+            config.check_against(&taken),
+            Err(ConfigError::MemoryBusy(0))
+        );
+
+        let mut taken = other.clone();
+        taken.memory_regions[0].phys_start = 0x0a00_0000;
+        assert_eq!(
+            config.check_against(&taken),
+            Err(ConfigError::DeviceBusy(0))
+        );
+
+        let mut taken = other;
+        taken.devices.push(config.devices[2]);
+        assert_eq!(config.check_against(&taken), Err(ConfigError::MacBusy(2)));
+    }
+}
@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 // 8MiB stack size per hart
 const DEFAULT_KERNEL_STACK_SIZE: usize = 8 * 1024 * 1024;
+// 64MiB heap, the rest of the memory is left to guests
+const DEFAULT_HEAP_SIZE: usize = 64 * 1024 * 1024;
 
 // const ENTRY_VADDR: u64 = 0x40200000;
 const ENTRY_VADDR: u64 = 0xE00000000000;
@@ -66,8 +68,9 @@ impl Arch {
 fn gen_const() {
     let const_content = format!(
         r#"pub const KERNEL_STACK_SIZE: usize = {:#x};
+            pub const HEAP_SIZE: usize = {:#x};
             "#,
-        DEFAULT_KERNEL_STACK_SIZE
+        DEFAULT_KERNEL_STACK_SIZE, DEFAULT_HEAP_SIZE
     );
 
     std::fs::write(out_dir().join("constant.rs"), const_content).expect("const write failed");
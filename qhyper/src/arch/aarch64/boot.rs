@@ -1,18 +1,25 @@
+use alloc::format;
 use core::arch::naked_asm;
 
 use aarch64_cpu::{asm::barrier, registers::*};
+use memory_addr::PhysAddrRange;
 
 use crate::{
-    arch::{cache, mmu},
+    arch::{cache, mmu, psci},
     debug::{self, dbg, dbg_hexln, dbgln},
+    error::HvError,
+    hv_err,
     mem::{self},
-    vm_main,
+    percpu::CPUHardId,
+    secondary_main, time, vm_main,
 };
 
 const FLAG_LE: usize = 0b0;
 const FLAG_PAGE_SIZE_4K: usize = 0b10;
 const FLAG_ANY_MEM: usize = 0b1000;
 
+const HCR_TWI: u64 = 1 << 13;
+
 #[naked]
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".text.head")]
@@ -69,6 +76,8 @@ unsafe extern "C" fn primary_entry() -> ! {
             // clear icache
             "ic  iallu",
             "BL       {clean_bss}",
+            "mrs      x0,  cntpct_el0",
+            "BL       {record_boot}",
             "MOV      x0,  x18",
             "BL       {set_va}",
             "BL       {switch_to_el2}",
@@ -81,6 +90,7 @@ unsafe extern "C" fn primary_entry() -> ! {
             this_func = sym primary_entry,
             switch_to_el2 = sym switch_to_el2,
             clean_bss = sym mem::clean_bss,
+            record_boot = sym time::record_boot,
             mmu_init = sym mmu::init,
             enable_fp = sym enable_fp,
             init_debug = sym init_debug,
@@ -90,6 +100,50 @@ unsafe extern "C" fn primary_entry() -> ! {
     }
 }
 
+#[naked]
+#[unsafe(link_section = ".text.boot")]
+/// The entry point of secondary cpus, `x0` is the stack top passed by `CPU_ON`.
+unsafe extern "C" fn secondary_entry() -> ! {
+    unsafe {
+        naked_asm!(
+            "MOV      x19, x0",        // x19 = stack_top
+            // disable cache and MMU
+            "mrs x1, sctlr_el2",
+            "bic x1, x1, #0xf",
+            "msr sctlr_el2, x1",
+            // setup stack
+            "MOV      sp,  x19",
+            // cache_invalidate(0): clear dl1$
+            "mov x0, #0",
+            "bl  {cache_invalidate}",
+            // clear icache
+            "ic  iallu",
+            "BL       {switch_to_el2}",
+            "BL       {enable_fp}",
+            "BL       {setup_el2}",
+            "MOV      x0,  x19",
+            "BL       {mmu_init_secondary}",
+            switch_to_el2 = sym switch_to_el2,
+            enable_fp = sym enable_fp,
+            setup_el2 = sym setup_el2,
+            mmu_init_secondary = sym mmu::init_secondary,
+            cache_invalidate = sym cache::cache_invalidate,
+        )
+    }
+}
+
+/// Power on cpu `id` through PSCI `CPU_ON`, it will run [`secondary_main`] on `stack`.
+pub fn cpu_on(id: CPUHardId, stack: PhysAddrRange) -> Result<(), HvError> {
+    mmu::share_table_with_secondary();
+    // The secondary writes its stack with caches off, drop lines dirtied by `alloc_zeroed`.
+    cache::dcache_clean_invalidate(stack.start.as_usize(), stack.size());
+
+    let entry = secondary_entry as *const () as usize - mem::va_offset();
+
+    psci::cpu_on(id.raw() as _, entry as _, stack.end.as_usize() as _)
+        .map_err(|e| hv_err!(EIO, format!("psci cpu_on {} failed: {:?}", id, e)))
+}
+
 fn set_va(va: usize) {
     unsafe {
         mem::set_va(va);
@@ -138,6 +192,10 @@ pub fn rust_main() -> ! {
     vm_main()
 }
 
+pub fn secondary_rust_main() -> ! {
+    secondary_main()
+}
+
 fn init_debug(fdt: *mut u8) -> Option<()> {
     let fdt = unsafe { mem::save_fdt(fdt) }?;
     debug::init_by_fdt(fdt);
@@ -165,4 +223,6 @@ fn setup_el2() {
             + HCR_EL2::FMO::EnableVirtualFIQ // Physical FIQ Routing.
             + HCR_EL2::TSC::EnableTrapEl1SmcToEl2,
     );
+    // Trap WFI, a waiting guest leaves the cpu to the hypervisor timers.
+    HCR_EL2.set(HCR_EL2.get() | HCR_TWI);
 }
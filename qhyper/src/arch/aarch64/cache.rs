@@ -32,3 +32,20 @@ pub unsafe fn cache_invalidate(cache_level: usize) {
         options(nostack)
     );
 }
+
+fn dcache_line_size() -> usize {
+    let ctr: usize;
+    unsafe { core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Clean and invalidate the data cache lines covering `[start, start + len)` to the point of coherency.
+pub fn dcache_clean_invalidate(start: usize, len: usize) {
+    let line = dcache_line_size();
+    let mut addr = start & !(line - 1);
+    while addr < start + len {
+        unsafe { core::arch::asm!("dc civac, {}", in(reg) addr) };
+        addr += line;
+    }
+    unsafe { core::arch::asm!("dsb sy") };
+}
@@ -1,7 +1,18 @@
-use aarch64_cpu::registers::*;
+use aarch64_cpu::{asm::wfi, registers::*};
+use log::debug;
+
+use super::{gic::vgic, timer, trap::enter_guest};
+use crate::{
+    percpu::cpu_data,
+    room::RoomId,
+    time::{
+        timer::{add_timer, cancel},
+        Instant,
+    },
+};
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct GeneralRegisters {
     pub exit_reason: u64,
     pub usr: [u64; 31],
@@ -14,155 +25,248 @@ impl GeneralRegisters {
     }
 }
 
-// #[repr(C)]
-// #[derive(Debug)]
-// pub struct ArchCpu {
-//     pub cpuid: usize,
-//     pub power_on: bool,
-// }
-
-// impl ArchCpu {
-//     pub fn new(cpuid: usize) -> Self {
-//         Self {
-//             cpuid,
-//             power_on: false,
-//         }
-//     }
-
-//     pub fn reset(&mut self, entry: usize, dtb: usize) {
-//         debug!(
-//             "cpu {} reset, entry: {:#x}, dtb: {:#x}",
-//             self.cpuid, entry, dtb
-//         );
-//         ELR_EL2.set(entry as _);
-//         SPSR_EL2.set(0x3c5);
-//         let regs = self.guest_reg();
-//         regs.clear();
-//         regs.usr[0] = dtb as _; // dtb addr
-//         self.reset_vm_regs();
-//         self.activate_vmm();
-//     }
-
-//     fn activate_vmm(&self) {
-//         VTCR_EL2.write(
-//             VTCR_EL2::TG0::Granule4KB
-//                 + VTCR_EL2::PS.val(get_parange() as _)
-//                 + VTCR_EL2::SH0::Inner
-//                 + VTCR_EL2::HA::Enabled
-//                 + VTCR_EL2::SL0.val(if is_s2_pt_level3() { 1 } else { 2 })
-//                 + VTCR_EL2::ORGN0::NormalWBRAWA
-//                 + VTCR_EL2::IRGN0::NormalWBRAWA
-//                 + VTCR_EL2::T0SZ.val(
-//                     64 - if is_s2_pt_level3() {
-//                         39
-//                     } else {
-//                         get_parange_bits() as _
-//                     },
-//                 ),
-//         );
-//         HCR_EL2.write(
-//             HCR_EL2::RW::EL1IsAarch64
-//                 + HCR_EL2::TSC::EnableTrapEl1SmcToEl2
-//                 + HCR_EL2::VM::SET
-//                 + HCR_EL2::IMO::SET
-//                 + HCR_EL2::FMO::SET,
-//         );
-//     }
-
-//     fn stack_top(&self) -> VirtAddr {
-//         PER_CPU_ARRAY_PTR as VirtAddr + (self.cpuid + 1) as usize * PER_CPU_SIZE
-//     }
-
-//     fn guest_reg(&self) -> &mut GeneralRegisters {
-//         unsafe { &mut *((self.stack_top() - 32 * 8) as *mut GeneralRegisters) }
-//     }
-
-//     fn reset_vm_regs(&self) {
-//         /* put the cpu in a reset state */
-//         /* AARCH64_TODO: handle big endian support */
-//         write_sysreg!(CNTKCTL_EL1, 0);
-//         write_sysreg!(PMCR_EL0, 0);
-
-//         // /* AARCH64_TODO: wipe floating point registers */
-//         // /* wipe special registers */
-//         write_sysreg!(SP_EL0, 0);
-//         write_sysreg!(SP_EL1, 0);
-//         write_sysreg!(SPSR_EL1, 0);
-
-//         // /* wipe the system registers */
-//         write_sysreg!(AFSR0_EL1, 0);
-//         write_sysreg!(AFSR1_EL1, 0);
-//         write_sysreg!(AMAIR_EL1, 0);
-//         write_sysreg!(CONTEXTIDR_EL1, 0);
-//         write_sysreg!(CPACR_EL1, 0);
-//         write_sysreg!(CSSELR_EL1, 0);
-//         write_sysreg!(ESR_EL1, 0);
-//         write_sysreg!(FAR_EL1, 0);
-//         write_sysreg!(MAIR_EL1, 0);
-//         write_sysreg!(PAR_EL1, 0);
-//         write_sysreg!(TCR_EL1, 0);
-//         write_sysreg!(TPIDRRO_EL0, 0);
-//         write_sysreg!(TPIDR_EL0, 0);
-//         write_sysreg!(TPIDR_EL1, 0);
-//         write_sysreg!(TTBR0_EL1, 0);
-//         write_sysreg!(TTBR1_EL1, 0);
-//         write_sysreg!(VBAR_EL1, 0);
-
-//         /* wipe timer registers */
-//         write_sysreg!(CNTVOFF_EL2, 0);
-//         write_sysreg!(CNTP_CTL_EL0, 0);
-//         write_sysreg!(CNTP_CVAL_EL0, 0);
-//         write_sysreg!(CNTP_TVAL_EL0, 0);
-//         write_sysreg!(CNTV_CTL_EL0, 0);
-//         write_sysreg!(CNTV_CVAL_EL0, 0);
-//         write_sysreg!(CNTV_TVAL_EL0, 0);
-//         // //disable stage 1
-//         // write_sysreg!(SCTLR_EL1, 0);
-
-//         SCTLR_EL1.set((1 << 11) | (1 << 20) | (3 << 22) | (3 << 28));
-//     }
-
-//     pub fn run(&mut self) -> ! {
-//         assert!(this_cpu_id() == self.cpuid);
-//         this_cpu_data().activate_gpm();
-//         self.reset(this_cpu_data().cpu_on_entry, this_cpu_data().dtb_ipa);
-//         self.power_on = true;
-//         info!("cpu {} started", self.cpuid);
-//         unsafe {
-//             vmreturn(self.guest_reg() as *mut _ as usize);
-//         }
-//     }
-
-//     pub fn idle(&mut self) -> ! {
-//         assert!(this_cpu_id() == self.cpuid);
-//         let cpu_data = this_cpu_data();
-//         let _lock = cpu_data.ctrl_lock.lock();
-//         self.power_on = false;
-//         drop(_lock);
-
-//         info!("cpu {} idle", self.cpuid);
-//         // reset current cpu -> pc = 0x0 (wfi)
-//         PARKING_MEMORY_SET.call_once(|| {
-//             let parking_code: [u8; 8] = [0x7f, 0x20, 0x03, 0xd5, 0xff, 0xff, 0xff, 0x17]; // 1: wfi; b 1b
-//             unsafe {
-//                 PARKING_INST_PAGE[..8].copy_from_slice(&parking_code);
-//             }
-
-//             let mut gpm = new_s2_memory_set();
-//             gpm.insert(MemoryRegion::new_with_offset_mapper(
-//                 0 as GuestPhysAddr,
-//                 unsafe { &PARKING_INST_PAGE as *const _ as HostPhysAddr - PHYS_VIRT_OFFSET },
-//                 PAGE_SIZE,
-//                 MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-//             ))
-//             .unwrap();
-//             gpm
-//         });
-//         self.reset(0, this_cpu_data().dtb_ipa);
-//         unsafe {
-//             PARKING_MEMORY_SET.get().unwrap().activate();
-//             info!("cpu {} started from parking", self.cpuid);
-//             vmreturn(self.guest_reg() as *mut _ as usize);
-//         }
-//     }
-// }
+macro_rules! read_sysreg {
+    ($name:ident) => {{
+        let v: u64;
+        unsafe { core::arch::asm!(concat!("mrs {}, ", stringify!($name)), out(reg) v) };
+        v
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($name:ident, $v:expr) => {
+        unsafe { core::arch::asm!(concat!("msr ", stringify!($name), ", {}"), in(reg) $v) }
+    };
+}
+
+macro_rules! el1_context {
+    ($($reg:ident),* $(,)?) => {
+        /// EL1 system registers of a guest, switched around every exit.
+        #[repr(C)]
+        #[derive(Debug, Clone, Default)]
+        pub struct El1Context {
+            $(pub $reg: u64,)*
+        }
+
+        impl El1Context {
+            pub fn save(&mut self) {
+                $(self.$reg = read_sysreg!($reg);)*
+            }
+
+            pub fn restore(&self) {
+                $(write_sysreg!($reg, self.$reg);)*
+            }
+        }
+    };
+}
+
+el1_context!(
+    sp_el0,
+    sp_el1,
+    elr_el1,
+    spsr_el1,
+    sctlr_el1,
+    cpacr_el1,
+    ttbr0_el1,
+    ttbr1_el1,
+    tcr_el1,
+    mair_el1,
+    amair_el1,
+    vbar_el1,
+    contextidr_el1,
+    esr_el1,
+    far_el1,
+    par_el1,
+    afsr0_el1,
+    afsr1_el1,
+    csselr_el1,
+    cntkctl_el1,
+    tpidr_el0,
+    tpidr_el1,
+    tpidrro_el0,
+    pmcr_el0,
+    // The compare value first, the timer may be enabled.
+    cntv_cval_el0,
+    cntv_ctl_el0,
+);
+
+/// EL1h with D, A, I, F masked.
+const SPSR_EL1H_DAIF: u64 = 0x3c5;
+
+/// `MPIDR_EL1` bit 31 reads as one.
+const MPIDR_RES1: u64 = 1 << 31;
+/// Vcpus per Aff1 value, as many as an SGI target list reaches.
+const VCPUS_PER_CLUSTER: usize = 16;
+
+/// The affinity guests see for their vcpu `n`: `n / 16` in Aff1 and `n % 16` in Aff0. It is
+/// what their device tree, PSCI, SGIs and interrupt routes use, vcpu `n` of a room runs on
+/// its cpu `n`.
+pub fn vmpidr(n: usize) -> u64 {
+    ((n / VCPUS_PER_CLUSTER) << 8 | (n % VCPUS_PER_CLUSTER)) as u64
+}
+
+/// The vcpu with affinity `mpidr`, the other bits ignored.
+pub fn vcpu_of(mpidr: u64) -> Option<usize> {
+    let aff0 = (mpidr & 0xff) as usize;
+    let aff1 = (mpidr >> 8 & 0xff) as usize;
+    (mpidr & 0xff_00ff_0000 == 0 && aff0 < VCPUS_PER_CLUSTER)
+        .then_some(aff1 * VCPUS_PER_CLUSTER + aff0)
+}
+
+/// Zero the FP/SIMD registers of this cpu, so a guest does not see those of the one before.
+/// The hypervisor does not use them, they are otherwise left to the guest as they are.
+fn clear_fp() {
+    unsafe {
+        core::arch::asm!(
+            ".arch_extension fp",
+            ".arch_extension simd",
+            "movi v0.2d, #0",
+            "movi v1.2d, #0",
+            "movi v2.2d, #0",
+            "movi v3.2d, #0",
+            "movi v4.2d, #0",
+            "movi v5.2d, #0",
+            "movi v6.2d, #0",
+            "movi v7.2d, #0",
+            "movi v8.2d, #0",
+            "movi v9.2d, #0",
+            "movi v10.2d, #0",
+            "movi v11.2d, #0",
+            "movi v12.2d, #0",
+            "movi v13.2d, #0",
+            "movi v14.2d, #0",
+            "movi v15.2d, #0",
+            "movi v16.2d, #0",
+            "movi v17.2d, #0",
+            "movi v18.2d, #0",
+            "movi v19.2d, #0",
+            "movi v20.2d, #0",
+            "movi v21.2d, #0",
+            "movi v22.2d, #0",
+            "movi v23.2d, #0",
+            "movi v24.2d, #0",
+            "movi v25.2d, #0",
+            "movi v26.2d, #0",
+            "movi v27.2d, #0",
+            "movi v28.2d, #0",
+            "movi v29.2d, #0",
+            "movi v30.2d, #0",
+            "movi v31.2d, #0",
+            "msr fpcr, xzr",
+            "msr fpsr, xzr",
+        )
+    }
+}
+
+/// A virtual cpu, runs a guest at EL1 on the physical cpu it is attached to.
+#[derive(Debug)]
+pub struct VCpu {
+    pub id: usize,
+    pub room: RoomId,
+    pub regs: GeneralRegisters,
+    pub elr: u64,
+    pub spsr: u64,
+    pub el1: El1Context,
+    pub power_on: bool,
+    /// `CNTVOFF_EL2` of the room, its guest counts from when it started.
+    pub cntvoff: u64,
+}
+
+impl VCpu {
+    pub fn new(id: usize, room: RoomId) -> Self {
+        Self {
+            id,
+            room,
+            regs: GeneralRegisters::default(),
+            elr: 0,
+            spsr: SPSR_EL1H_DAIF,
+            el1: El1Context::default(),
+            power_on: false,
+            cntvoff: 0,
+        }
+    }
+
+    /// Put the vcpu in its reset state, it will start at `entry` with `x0` = `dtb`, or the
+    /// context id of a `CPU_ON`.
+    pub fn reset(&mut self, entry: usize, dtb: usize) {
+        debug!(
+            "vcpu {} reset, entry: {:#x}, dtb: {:#x}",
+            self.id, entry, dtb
+        );
+        self.regs.clear();
+        self.regs.usr[0] = dtb as _;
+        self.elr = entry as _;
+        self.spsr = SPSR_EL1H_DAIF;
+        self.el1 = El1Context {
+            // RES1 bits, MMU and caches off
+            sctlr_el1: (1 << 11) | (1 << 20) | (3 << 22) | (3 << 28),
+            ..Default::default()
+        };
+        clear_fp();
+    }
+
+    /// `xn`, 31 is the zero register.
+    pub fn reg(&self, n: usize) -> u64 {
+        match n {
+            31 => 0,
+            n => self.regs.usr[n],
+        }
+    }
+
+    pub fn set_reg(&mut self, n: usize, value: u64) {
+        if n < 31 {
+            self.regs.usr[n] = value;
+        }
+    }
+
+    /// The stack pointer selected at the level the guest trapped from.
+    pub fn sp(&self) -> u64 {
+        match self.spsr & 1 {
+            1 => self.el1.sp_el1,
+            _ => self.el1.sp_el0,
+        }
+    }
+
+    pub fn set_sp(&mut self, value: u64) {
+        match self.spsr & 1 {
+            1 => self.el1.sp_el1 = value,
+            _ => self.el1.sp_el0 = value,
+        }
+    }
+
+    /// Save the guest state left by an exit, `frame` is the trap frame on the stack.
+    pub fn save(&mut self, frame: &GeneralRegisters) {
+        self.regs = frame.clone();
+        self.elr = ELR_EL2.get();
+        self.spsr = SPSR_EL2.get();
+        self.el1.save();
+    }
+
+    /// The guest waits for an interrupt (trapped `wfi`). Its virtual timer is stopped in the
+    /// meantime, a hypervisor timer wakes the cpu at its deadline and the restored timer then
+    /// fires into the guest.
+    pub fn wait(&mut self) {
+        let wakeup =
+            timer::virtual_deadline(self.el1.cntv_ctl_el0, self.el1.cntv_cval_el0, self.cntvoff)
+                .map(|deadline| add_timer(Instant::from_ticks(deadline), || {}));
+        timer::stop_virtual();
+        if !vgic::pending() {
+            wfi();
+        }
+        if let Some(id) = wakeup {
+            cancel(id);
+        }
+    }
+
+    /// Restore the guest state and `eret` into it.
+    pub fn run(&mut self) -> ! {
+        vgic::flush();
+        timer::set_virtual_offset(self.cntvoff);
+        write_sysreg!(vmpidr_el2, vmpidr(self.id) | MPIDR_RES1);
+        self.el1.restore();
+        ELR_EL2.set(self.elr);
+        SPSR_EL2.set(self.spsr);
+        self.power_on = true;
+        unsafe { enter_guest(&self.regs, cpu_data().stack_top()) }
+    }
+}
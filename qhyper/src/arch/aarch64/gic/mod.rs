@@ -0,0 +1,405 @@
+//! GIC driver for the interrupts the hypervisor takes itself, GICv2 or GICv3 as described
+//! by the fdt `interrupt-controller` node.
+//!
+//! Both run with split priority drop and deactivation (EOImode 1): an interrupt is dropped
+//! right after it is acknowledged, and deactivated once its handler returned, or by the guest
+//! it is forwarded to.
+
+use alloc::{collections::btree_map::BTreeMap, format};
+use arrayvec::ArrayVec;
+use fdt_parser::Fdt;
+use log::{debug, info, warn};
+use memory_addr::{pa_range, PhysAddrRange};
+use page_table_generic::{AccessSetting, CacheSetting};
+use spin::Mutex;
+
+use crate::{
+    error::HvResult,
+    hv_err, hv_result_err,
+    mem::{get_fdt, once::OnceStatic, space::Space},
+    percpu::CPUHardId,
+};
+
+use super::cpu_id;
+
+mod v2;
+mod v3;
+pub mod vdist;
+pub mod vgic;
+
+/// SGI making a cpu leave its guest to look at the requests posted to it.
+pub const SGI_KICK: u32 = 0;
+/// First shared peripheral interrupt.
+pub const SPI_BASE: u32 = 32;
+/// INTIDs from here are special, 1023 is a spurious acknowledge.
+const INTID_SPECIAL: u32 = 1020;
+/// Priority of every interrupt, the mask lets everything above the lowest one through.
+const PRIORITY_DEFAULT: u8 = 0xa0;
+const PRIORITY_MASK: u8 = 0xf0;
+
+/// Called on the cpu that took the interrupt, with interrupts masked.
+pub type IrqHandler = fn(intid: u32);
+
+#[derive(Clone, Copy)]
+enum IrqAction {
+    Handler(IrqHandler),
+    /// Injected into the guest of the cpu with the HW bit, the guest deactivates it.
+    Forward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicVersion {
+    V2,
+    V3,
+}
+
+/// The GIC frames found in the fdt.
+#[derive(Debug, Clone)]
+pub struct GicInfo {
+    pub version: GicVersion,
+    pub gicd: PhysAddrRange,
+    /// Redistributor regions, v3 only.
+    pub gicr: ArrayVec<PhysAddrRange, 4>,
+    /// Memory mapped cpu interface, optional on v3.
+    pub gicc: Option<PhysAddrRange>,
+    /// Virtual interface control.
+    pub gich: Option<PhysAddrRange>,
+    /// Virtual cpu interface, given to guests in place of GICC.
+    pub gicv: Option<PhysAddrRange>,
+    /// The maintenance interrupt, a PPI.
+    pub maintenance_irq: Option<u32>,
+}
+
+static GIC: OnceStatic<Option<GicInfo>> = OnceStatic::new(None);
+static HANDLERS: Mutex<BTreeMap<u32, IrqAction>> = Mutex::new(BTreeMap::new());
+
+const COMPATIBLE_V2: [&str; 3] = ["arm,cortex-a15-gic", "arm,gic-400", "arm,cortex-a9-gic"];
+const COMPATIBLE_V3: [&str; 1] = ["arm,gic-v3"];
+
+/// Find the GIC in the fdt, must run before the hypervisor page table is built.
+pub fn probe() {
+    let Some(fdt) = get_fdt() else {
+        return;
+    };
+    match probe_by_fdt(&fdt) {
+        Some(info) => {
+            info!("GIC {:?} at {:?}", info.version, info.gicd);
+            unsafe { GIC.set(Some(info)) };
+        }
+        None => warn!("no GIC found in fdt"),
+    }
+}
+
+fn probe_by_fdt(fdt: &Fdt) -> Option<GicInfo> {
+    let (node, version) = match fdt.find_compatible(&COMPATIBLE_V3).next() {
+        Some(node) => (node, GicVersion::V3),
+        None => (fdt.find_compatible(&COMPATIBLE_V2).next()?, GicVersion::V2),
+    };
+    let regs = node
+        .reg()?
+        .map(|r| {
+            let start = r.address as usize;
+            pa_range!(start..start + r.size.unwrap_or(0))
+        })
+        .collect::<ArrayVec<_, 8>>();
+    let maintenance_irq = node
+        .interrupts()
+        .and_then(|mut irqs| irqs.next())
+        .and_then(|mut cells| Some(cells.nth(1)? + 16));
+
+    let mut info = GicInfo {
+        version,
+        gicd: *regs.first()?,
+        gicr: ArrayVec::new(),
+        gicc: None,
+        gich: None,
+        gicv: None,
+        maintenance_irq,
+    };
+    let rest = match version {
+        GicVersion::V2 => &regs[1..],
+        GicVersion::V3 => {
+            let nr_gicr = node
+                .find_property("#redistributor-regions")
+                .map_or(1, |p| p.u32() as usize);
+            let nr_gicr = nr_gicr.min(regs.len() - 1).min(info.gicr.capacity());
+            info.gicr.extend(regs[1..1 + nr_gicr].iter().copied());
+            &regs[1 + nr_gicr..]
+        }
+    };
+    let mut rest = rest.iter().copied();
+    info.gicc = rest.next();
+    info.gich = rest.next();
+    info.gicv = rest.next();
+    Some(info)
+}
+
+pub fn info() -> Option<&'static GicInfo> {
+    GIC.as_ref()
+}
+
+fn version() -> Option<GicVersion> {
+    info().map(|info| info.version)
+}
+
+/// The frames the hypervisor accesses, identity mapped. GICV is only for guests.
+pub fn spaces() -> ArrayVec<Space, 8> {
+    let mut spaces = ArrayVec::new();
+    let Some(info) = info() else {
+        return spaces;
+    };
+    let device = |name, phys| Space {
+        name,
+        phys,
+        offset: 0,
+        access: AccessSetting::Read | AccessSetting::Write,
+        cache: CacheSetting::Device,
+    };
+    spaces.push(device("gicd", info.gicd));
+    for &gicr in &info.gicr {
+        spaces.push(device("gicr", gicr));
+    }
+    // The cpu interfaces are never given to guests as they are either.
+    if let Some(gicc) = info.gicc {
+        spaces.push(device("gicc", gicc));
+    }
+    if let Some(gich) = info.gich {
+        spaces.push(device("gich", gich));
+    }
+    if let Some(gicv) = info.gicv {
+        spaces.push(device("gicv", gicv));
+    }
+    spaces
+}
+
+/// Set up the distributor and the interface of the boot cpu.
+pub fn init() {
+    match version() {
+        Some(GicVersion::V2) => v2::init_distributor(),
+        Some(GicVersion::V3) => v3::init_distributor(),
+        None => return,
+    }
+    let _ = register_handler(SGI_KICK, |_| {});
+    vgic::init();
+    init_cpu();
+    debug!("GIC distributor ready");
+}
+
+/// Set up the interface of the current cpu, before it takes any interrupt.
+pub fn init_cpu() {
+    match version() {
+        Some(GicVersion::V2) => v2::init_cpu(),
+        Some(GicVersion::V3) => v3::init_cpu(),
+        None => return,
+    }
+    enable_irq(SGI_KICK);
+    vgic::init_cpu();
+}
+
+/// Service every pending interrupt of the current cpu.
+pub fn handle_irq() {
+    let Some(version) = version() else {
+        warn!("irq without GIC");
+        return;
+    };
+    loop {
+        let iar = match version {
+            GicVersion::V2 => v2::ack(),
+            GicVersion::V3 => v3::ack(),
+        };
+        let intid = iar & 0x3ff;
+        if intid >= INTID_SPECIAL {
+            break;
+        }
+        match version {
+            GicVersion::V2 => v2::eoi(iar),
+            GicVersion::V3 => v3::eoi(iar),
+        }
+
+        let action = HANDLERS.lock().get(&intid).copied();
+        match action {
+            Some(IrqAction::Handler(handler)) => handler(intid),
+            Some(IrqAction::Forward) if vgic::forward(intid) => continue,
+            Some(IrqAction::Forward) => {
+                debug!("irq {} forwarded without guest, disabled", intid);
+                disable_irq(intid);
+            }
+            None => {
+                warn!("irq {} has no handler, disabled", intid);
+                disable_irq(intid);
+            }
+        }
+
+        match version {
+            GicVersion::V2 => v2::deactivate(iar),
+            GicVersion::V3 => v3::deactivate(iar),
+        }
+    }
+}
+
+/// Call `handler` for `intid`, which must be enabled separately, on each cpu for SGIs and
+/// PPIs.
+pub fn register_handler(intid: u32, handler: IrqHandler) -> HvResult {
+    let mut handlers = HANDLERS.lock();
+    if handlers.contains_key(&intid) {
+        return hv_result_err!(EEXIST, format!("irq {} already has a handler", intid));
+    }
+    handlers.insert(intid, IrqAction::Handler(handler));
+    Ok(())
+}
+
+pub fn unregister_handler(intid: u32) {
+    HANDLERS.lock().remove(&intid);
+}
+
+/// Hand `intid` to the guest running on the cpu it is routed to and enable it, SPIs are
+/// routed with [`route_irq`].
+pub fn forward_irq(intid: u32) -> HvResult {
+    let mut handlers = HANDLERS.lock();
+    match handlers.get(&intid) {
+        Some(IrqAction::Handler(_)) => {
+            return hv_result_err!(EBUSY, format!("irq {} is used by the hypervisor", intid))
+        }
+        Some(IrqAction::Forward) => {}
+        None => {
+            handlers.insert(intid, IrqAction::Forward);
+        }
+    }
+    drop(handlers);
+    set_enable(intid, true);
+    Ok(())
+}
+
+/// Stop forwarding `intid`, it is disabled.
+pub fn unforward_irq(intid: u32) {
+    let mut handlers = HANDLERS.lock();
+    if let Some(IrqAction::Forward) = handlers.get(&intid) {
+        handlers.remove(&intid);
+        disable_irq(intid);
+    }
+}
+
+/// Let `intid` through, SPIs are routed to the current cpu.
+pub fn enable_irq(intid: u32) {
+    if intid >= SPI_BASE {
+        route_irq(intid, cpu_id());
+    }
+    set_enable(intid, true);
+}
+
+pub fn disable_irq(intid: u32) {
+    set_enable(intid, false);
+}
+
+fn set_enable(intid: u32, enable: bool) {
+    match version() {
+        Some(GicVersion::V2) => v2::set_enable(intid, enable),
+        Some(GicVersion::V3) => v3::set_enable(intid, enable),
+        None => {}
+    }
+}
+
+/// Deliver SPI `intid` to cpu `cpu`.
+pub fn route_irq(intid: u32, cpu: CPUHardId) {
+    match version() {
+        Some(GicVersion::V2) => v2::route(intid, cpu),
+        Some(GicVersion::V3) => v3::route(intid, cpu),
+        None => {}
+    }
+}
+
+/// Raise SGI `sgi` on cpu `cpu`.
+pub fn send_sgi(cpu: CPUHardId, sgi: u32) -> HvResult {
+    if sgi >= 16 {
+        return hv_result_err!(EINVAL, format!("{} is not an SGI", sgi));
+    }
+    match version() {
+        Some(GicVersion::V2) => v2::send_sgi(cpu, sgi),
+        Some(GicVersion::V3) => {
+            v3::send_sgi(cpu, sgi);
+            Ok(())
+        }
+        None => Err(hv_err!(ENODEV, "no GIC")),
+    }
+}
+
+fn read32(addr: usize) -> u32 {
+    unsafe { (addr as *const u32).read_volatile() }
+}
+
+fn write32(addr: usize, value: u32) {
+    unsafe { (addr as *mut u32).write_volatile(value) }
+}
+
+fn write8(addr: usize, value: u8) {
+    unsafe { (addr as *mut u8).write_volatile(value) }
+}
+
+/// A register access of `width` bytes, for the emulated frames.
+fn read_width(addr: usize, width: usize) -> u64 {
+    unsafe {
+        match width {
+            1 => (addr as *const u8).read_volatile() as u64,
+            2 => (addr as *const u16).read_volatile() as u64,
+            4 => (addr as *const u32).read_volatile() as u64,
+            _ => (addr as *const u64).read_volatile(),
+        }
+    }
+}
+
+fn write_width(addr: usize, width: usize, value: u64) {
+    unsafe {
+        match width {
+            1 => (addr as *mut u8).write_volatile(value as u8),
+            2 => (addr as *mut u16).write_volatile(value as u16),
+            4 => (addr as *mut u32).write_volatile(value as u32),
+            _ => (addr as *mut u64).write_volatile(value),
+        }
+    }
+}
+
+/// The number of interrupt lines the distributor implements, from `GICD_TYPER`.
+fn nr_lines(gicd: usize) -> u32 {
+    (32 * ((read32(gicd + GICD_TYPER) & 0x1f) + 1)).min(INTID_SPECIAL)
+}
+
+// Distributor registers shared by both versions.
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_ICPENDR: usize = 0x0280;
+const GICD_ICACTIVER: usize = 0x0380;
+const GICD_IPRIORITYR: usize = 0x0400;
+
+/// Disable, clear and set a default priority for the SPIs, put them in group 1 if `group1`,
+/// otherwise leave the groups as they are.
+fn reset_spis(gicd: usize, group1: bool) {
+    let lines = nr_lines(gicd);
+    for i in (SPI_BASE..lines).step_by(32) {
+        let reg = (i / 32) as usize * 4;
+        write32(gicd + GICD_ICENABLER + reg, u32::MAX);
+        write32(gicd + GICD_ICPENDR + reg, u32::MAX);
+        write32(gicd + GICD_ICACTIVER + reg, u32::MAX);
+        if group1 {
+            write32(gicd + GICD_IGROUPR + reg, u32::MAX);
+        }
+    }
+    for i in SPI_BASE..lines {
+        write8(gicd + GICD_IPRIORITYR + i as usize, PRIORITY_DEFAULT);
+    }
+}
+
+/// The same for the SGIs and PPIs of one cpu, in the GICD (v2) or its GICR SGI frame (v3).
+fn reset_private(base: usize, group1: bool) {
+    write32(base + GICD_ICENABLER, u32::MAX);
+    write32(base + GICD_ICPENDR, u32::MAX);
+    write32(base + GICD_ICACTIVER, u32::MAX);
+    if group1 {
+        write32(base + GICD_IGROUPR, u32::MAX);
+    }
+    for i in 0..SPI_BASE as usize {
+        write8(base + GICD_IPRIORITYR + i, PRIORITY_DEFAULT);
+    }
+}
@@ -0,0 +1,165 @@
+//! GICv2: memory mapped distributor and cpu interface.
+
+use alloc::{collections::btree_map::BTreeMap, format};
+use spin::Mutex;
+
+use crate::{arch::cpu_id, error::HvResult, hv_err, percpu::CPUHardId};
+
+use super::{
+    info, read32, reset_private, reset_spis, vgic::Lr, write32, write8, GICD_CTLR, GICD_ICENABLER,
+    GICD_ISENABLER, PRIORITY_MASK,
+};
+
+const GICD_ITARGETSR: usize = 0x0800;
+const GICD_SGIR: usize = 0x0f00;
+
+const GICC_CTLR: usize = 0x0000;
+const GICC_PMR: usize = 0x0004;
+const GICC_BPR: usize = 0x0008;
+const GICC_IAR: usize = 0x000c;
+const GICC_EOIR: usize = 0x0010;
+const GICC_DIR: usize = 0x1000;
+
+const GICH_HCR: usize = 0x0000;
+const GICH_VTR: usize = 0x0004;
+const GICH_VMCR: usize = 0x0008;
+const GICH_ELRSR0: usize = 0x0030;
+const GICH_ELRSR1: usize = 0x0034;
+const GICH_APR: usize = 0x00f0;
+const GICH_LR: usize = 0x0100;
+
+const GICH_LR_PENDING: u32 = 1 << 28;
+const GICH_LR_ACTIVE: u32 = 1 << 29;
+const GICH_LR_HW: u32 = 1 << 31;
+
+// Bit 0 enables group 1 in the non-secure view of a GIC with the Security Extensions, and
+// group 0 without them, where bit 9 is then `EOImodeS` instead of `EOImodeNS`. Either way
+// they cover the interrupts the hypervisor gets with the groups left as they are.
+const GICD_CTLR_ENABLE: u32 = 1;
+const GICC_CTLR_ENABLE: u32 = 1;
+const GICC_CTLR_EOIMODE: u32 = 1 << 9;
+
+/// The cpu interface number of each cpu, as `GICD_ITARGETSR` and `GICD_SGIR` want it.
+static TARGETS: Mutex<BTreeMap<CPUHardId, u8>> = Mutex::new(BTreeMap::new());
+
+fn gicd() -> usize {
+    info().unwrap().gicd.start.as_usize()
+}
+
+fn gicc() -> usize {
+    info().unwrap().gicc.unwrap().start.as_usize()
+}
+
+fn gich() -> usize {
+    info().unwrap().gich.unwrap().start.as_usize()
+}
+
+/// The groups are left alone like Linux does: the secure firmware put the interrupts of the
+/// non-secure world in group 1, and without the Security Extensions group 0 is signalled as
+/// IRQ.
+pub fn init_distributor() {
+    let gicd = gicd();
+    write32(gicd + GICD_CTLR, 0);
+    reset_spis(gicd, false);
+    write32(gicd + GICD_CTLR, GICD_CTLR_ENABLE);
+}
+
+pub fn init_cpu() {
+    let gicd = gicd();
+    let gicc = gicc();
+
+    // The banked targets of the SGIs read as this cpu.
+    let target = read32(gicd + GICD_ITARGETSR) as u8;
+    TARGETS.lock().insert(cpu_id(), target);
+
+    reset_private(gicd, false);
+    write32(gicc + GICC_PMR, PRIORITY_MASK as _);
+    write32(gicc + GICC_BPR, 0);
+    write32(gicc + GICC_CTLR, GICC_CTLR_ENABLE | GICC_CTLR_EOIMODE);
+}
+
+pub fn ack() -> u32 {
+    read32(gicc() + GICC_IAR)
+}
+
+pub fn eoi(iar: u32) {
+    write32(gicc() + GICC_EOIR, iar);
+}
+
+pub fn deactivate(iar: u32) {
+    write32(gicc() + GICC_DIR, iar);
+}
+
+pub fn route(intid: u32, cpu: CPUHardId) {
+    if let Some(&target) = TARGETS.lock().get(&cpu) {
+        write8(gicd() + GICD_ITARGETSR + intid as usize, target);
+    }
+}
+
+pub fn set_enable(intid: u32, enable: bool) {
+    let gicd = gicd();
+    let reg = match enable {
+        true => GICD_ISENABLER,
+        false => GICD_ICENABLER,
+    };
+    write32(gicd + reg + (intid / 32) as usize * 4, 1 << (intid % 32));
+}
+
+pub fn send_sgi(cpu: CPUHardId, sgi: u32) -> HvResult {
+    let target = *TARGETS
+        .lock()
+        .get(&cpu)
+        .ok_or_else(|| hv_err!(ENODEV, format!("cpu {} has no GIC interface", cpu)))?;
+    write32(gicd() + GICD_SGIR, (target as u32) << 16 | sgi);
+    Ok(())
+}
+
+pub fn lr_count() -> usize {
+    (read32(gich() + GICH_VTR) & 0x3f) as usize + 1
+}
+
+pub fn empty_lrs() -> u64 {
+    read32(gich() + GICH_ELRSR0) as u64 | (read32(gich() + GICH_ELRSR1) as u64) << 32
+}
+
+pub fn read_lr(n: usize) -> Lr {
+    let lr = read32(gich() + GICH_LR + n * 4);
+    Lr {
+        intid: lr & 0x3ff,
+        hw: (lr & GICH_LR_HW != 0).then_some(lr >> 10 & 0x3ff),
+        priority: ((lr >> 23 & 0x1f) << 3) as u8,
+        pending: lr & GICH_LR_PENDING != 0,
+        active: lr & GICH_LR_ACTIVE != 0,
+    }
+}
+
+pub fn write_lr(n: usize, lr: Lr) {
+    let mut value = lr.intid & 0x3ff | (lr.priority as u32 >> 3) << 23;
+    if let Some(pintid) = lr.hw {
+        value |= GICH_LR_HW | (pintid & 0x3ff) << 10;
+    }
+    if lr.pending {
+        value |= GICH_LR_PENDING;
+    }
+    if lr.active {
+        value |= GICH_LR_ACTIVE;
+    }
+    write32(gich() + GICH_LR + n * 4, value);
+}
+
+pub fn hcr() -> u32 {
+    read32(gich() + GICH_HCR)
+}
+
+pub fn set_hcr(value: u32) {
+    write32(gich() + GICH_HCR, value);
+}
+
+/// Clear the list registers and the state the guest left in the virtual interface.
+pub fn reset_virtual() {
+    for n in 0..lr_count() {
+        write32(gich() + GICH_LR + n * 4, 0);
+    }
+    write32(gich() + GICH_APR, 0);
+    write32(gich() + GICH_VMCR, 0);
+}
@@ -0,0 +1,263 @@
+//! GICv3: memory mapped distributor and redistributors, system register cpu interface.
+
+use core::hint::spin_loop;
+
+use aarch64_cpu::{asm::barrier, registers::*};
+
+use crate::percpu::CPUHardId;
+
+use super::{
+    info, read32, reset_private, reset_spis, vgic::Lr, write32, GICD_CTLR, GICD_ICENABLER,
+    GICD_ISENABLER, PRIORITY_MASK, SPI_BASE,
+};
+
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_ENABLE_G1: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_G1A: u32 = 1 << 1;
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+const GICR_CTLR: usize = 0x0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+/// The SGI frame follows the RD frame of a redistributor.
+const GICR_SGI_BASE: usize = 0x1_0000;
+
+const GICR_CTLR_RWP: u32 = 1 << 3;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+const ICC_SRE_SRE: u64 = 1 << 0;
+const ICC_SRE_DFB: u64 = 1 << 1;
+const ICC_SRE_DIB: u64 = 1 << 2;
+const ICC_SRE_ENABLE: u64 = 1 << 3;
+const ICC_CTLR_EOIMODE: u64 = 1 << 1;
+
+const ICH_LR_GROUP1: u64 = 1 << 60;
+const ICH_LR_HW: u64 = 1 << 61;
+const ICH_LR_PENDING: u64 = 1 << 62;
+const ICH_LR_ACTIVE: u64 = 1 << 63;
+
+macro_rules! ich_lrs {
+    ($($n:literal => $reg:ident),* $(,)?) => {
+        fn read_lr_raw(n: usize) -> u64 {
+            match n {
+                $($n => read_sysreg!($reg),)*
+                _ => unreachable!(),
+            }
+        }
+
+        fn write_lr_raw(n: usize, value: u64) {
+            match n {
+                $($n => write_sysreg!($reg, value),)*
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+ich_lrs!(
+    0 => ich_lr0_el2,
+    1 => ich_lr1_el2,
+    2 => ich_lr2_el2,
+    3 => ich_lr3_el2,
+    4 => ich_lr4_el2,
+    5 => ich_lr5_el2,
+    6 => ich_lr6_el2,
+    7 => ich_lr7_el2,
+    8 => ich_lr8_el2,
+    9 => ich_lr9_el2,
+    10 => ich_lr10_el2,
+    11 => ich_lr11_el2,
+    12 => ich_lr12_el2,
+    13 => ich_lr13_el2,
+    14 => ich_lr14_el2,
+    15 => ich_lr15_el2,
+);
+
+fn gicd() -> usize {
+    info().unwrap().gicd.start.as_usize()
+}
+
+fn wait_rwp(addr: usize, bit: u32) {
+    while read32(addr) & bit != 0 {
+        spin_loop();
+    }
+}
+
+/// Aff3.Aff2.Aff1.Aff0 of an MPIDR, as `GICR_TYPER` and `GICD_IROUTER` hold them.
+pub fn affinity(mpidr: u64) -> u64 {
+    (mpidr & 0xff_ffff) | (mpidr >> 32 & 0xff) << 24
+}
+
+/// The redistributor of the current cpu.
+fn this_rd() -> usize {
+    let aff = affinity(MPIDR_EL1.get());
+    for region in &info().unwrap().gicr {
+        let mut rd = region.start.as_usize();
+        while rd < region.end.as_usize() {
+            let typer = unsafe { ((rd + GICR_TYPER) as *const u64).read_volatile() };
+            if typer >> 32 == aff {
+                return rd;
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                break;
+            }
+            rd += match typer & GICR_TYPER_VLPIS {
+                0 => 0x2_0000,
+                _ => 0x4_0000,
+            };
+        }
+    }
+    panic!("no redistributor for mpidr {:#x}", MPIDR_EL1.get());
+}
+
+pub fn init_distributor() {
+    let gicd = gicd();
+    write32(gicd + GICD_CTLR, 0);
+    wait_rwp(gicd + GICD_CTLR, GICD_CTLR_RWP);
+    reset_spis(gicd, true);
+    write32(
+        gicd + GICD_CTLR,
+        GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A | GICD_CTLR_ENABLE_G1,
+    );
+    wait_rwp(gicd + GICD_CTLR, GICD_CTLR_RWP);
+}
+
+pub fn init_cpu() {
+    let rd = this_rd();
+
+    write32(
+        rd + GICR_WAKER,
+        read32(rd + GICR_WAKER) & !GICR_WAKER_PROCESSOR_SLEEP,
+    );
+    while read32(rd + GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+        spin_loop();
+    }
+    reset_private(rd + GICR_SGI_BASE, true);
+    wait_rwp(rd + GICR_CTLR, GICR_CTLR_RWP);
+
+    // Guests may use the system registers too.
+    write_sysreg!(
+        icc_sre_el2,
+        ICC_SRE_SRE | ICC_SRE_DFB | ICC_SRE_DIB | ICC_SRE_ENABLE
+    );
+    barrier::isb(barrier::SY);
+    write_sysreg!(icc_pmr_el1, PRIORITY_MASK as u64);
+    write_sysreg!(icc_bpr1_el1, 0u64);
+    write_sysreg!(icc_ctlr_el1, ICC_CTLR_EOIMODE);
+    write_sysreg!(icc_igrpen1_el1, 1u64);
+    barrier::isb(barrier::SY);
+}
+
+pub fn ack() -> u32 {
+    let iar = read_sysreg!(icc_iar1_el1) as u32;
+    barrier::dsb(barrier::SY);
+    iar
+}
+
+pub fn eoi(iar: u32) {
+    write_sysreg!(icc_eoir1_el1, iar as u64);
+    barrier::isb(barrier::SY);
+}
+
+pub fn deactivate(iar: u32) {
+    write_sysreg!(icc_dir_el1, iar as u64);
+    barrier::isb(barrier::SY);
+}
+
+pub fn set_enable(intid: u32, enable: bool) {
+    let reg = match enable {
+        true => GICD_ISENABLER,
+        false => GICD_ICENABLER,
+    };
+    if intid < SPI_BASE {
+        let rd = this_rd();
+        write32(rd + GICR_SGI_BASE + reg, 1 << intid);
+        wait_rwp(rd + GICR_CTLR, GICR_CTLR_RWP);
+        return;
+    }
+
+    let gicd = gicd();
+    write32(gicd + reg + (intid / 32) as usize * 4, 1 << (intid % 32));
+    wait_rwp(gicd + GICD_CTLR, GICD_CTLR_RWP);
+}
+
+/// `GICD_IROUTER` has the layout of MPIDR.
+pub fn route(intid: u32, cpu: CPUHardId) {
+    let router = (gicd() + GICD_IROUTER + intid as usize * 8) as *mut u64;
+    unsafe { router.write_volatile(cpu.raw() as u64) };
+}
+
+/// `ICC_SGI1R_EL1` value targeting the cpu of affinity `mpidr`.
+pub fn sgi1r(mpidr: u64, sgi: u32) -> u64 {
+    let aff0 = mpidr & 0xff;
+    (mpidr >> 32 & 0xff) << 48
+        | (mpidr >> 16 & 0xff) << 32
+        | (aff0 >> 4) << 44
+        | (sgi as u64) << 24
+        | (mpidr >> 8 & 0xff) << 16
+        | 1 << (aff0 & 0xf)
+}
+
+pub fn send_sgi(cpu: CPUHardId, sgi: u32) {
+    barrier::dsb(barrier::ISHST);
+    write_sysreg!(icc_sgi1r_el1, sgi1r(cpu.raw() as u64, sgi));
+    barrier::isb(barrier::SY);
+}
+
+pub fn lr_count() -> usize {
+    (read_sysreg!(ich_vtr_el2) & 0x1f) as usize + 1
+}
+
+pub fn empty_lrs() -> u64 {
+    read_sysreg!(ich_elrsr_el2)
+}
+
+pub fn read_lr(n: usize) -> Lr {
+    let lr = read_lr_raw(n);
+    Lr {
+        intid: lr as u32,
+        hw: (lr & ICH_LR_HW != 0).then_some((lr >> 32 & 0x1fff) as u32),
+        priority: (lr >> 48) as u8,
+        pending: lr & ICH_LR_PENDING != 0,
+        active: lr & ICH_LR_ACTIVE != 0,
+    }
+}
+
+pub fn write_lr(n: usize, lr: Lr) {
+    let mut value = lr.intid as u64 | (lr.priority as u64) << 48 | ICH_LR_GROUP1;
+    if let Some(pintid) = lr.hw {
+        value |= ICH_LR_HW | (pintid as u64 & 0x1fff) << 32;
+    }
+    if lr.pending {
+        value |= ICH_LR_PENDING;
+    }
+    if lr.active {
+        value |= ICH_LR_ACTIVE;
+    }
+    write_lr_raw(n, value);
+}
+
+pub fn hcr() -> u32 {
+    read_sysreg!(ich_hcr_el2) as u32
+}
+
+pub fn set_hcr(value: u32) {
+    write_sysreg!(ich_hcr_el2, value as u64);
+    barrier::isb(barrier::SY);
+}
+
+/// Clear the list registers and the state the guest left in the virtual interface.
+pub fn reset_virtual() {
+    for n in 0..lr_count() {
+        write_lr_raw(n, 0);
+    }
+    write_sysreg!(ich_ap0r0_el2, 0u64);
+    write_sysreg!(ich_ap1r0_el2, 0u64);
+    write_sysreg!(ich_vmcr_el2, 0u64);
+    barrier::isb(barrier::SY);
+}
@@ -0,0 +1,444 @@
+//! Emulated distributor, and redistributors on v3, given to every room in place of the real
+//! ones. A room only sees the SPIs it owns: its writes to them are forwarded to the physical
+//! distributor, reads of other interrupts return 0.
+//!
+//! SGIs are virtual, they are injected into the target vcpus. The PPIs of a cpu are enabled
+//! as forwarded interrupts from the cpu itself.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    arch::{vcpu_of, vmpidr},
+    device::mmio::{MmioBus, MmioHandler},
+    error::HvResult,
+    percpu::{cpu_data, CPUHardId, CPUId},
+    room::{self, RoomId},
+};
+
+use super::{
+    forward_irq, info, nr_lines, read32, read_width, route_irq, unforward_irq, v3,
+    vgic::{self, Virq},
+    write_width, GicVersion, GICD_CTLR, GICD_ICACTIVER, GICD_ICENABLER, GICD_ICPENDR, GICD_IGROUPR,
+    GICD_IPRIORITYR, GICD_ISENABLER, GICD_TYPER, SPI_BASE,
+};
+
+const GICD_IIDR: usize = 0x0008;
+const GICD_TYPER2: usize = 0x000c;
+const GICD_ISPENDR: usize = 0x0200;
+const GICD_ISACTIVER: usize = 0x0300;
+const GICD_ITARGETSR: usize = 0x0800;
+const GICD_ICFGR: usize = 0x0c00;
+const GICD_SGIR: usize = 0x0f00;
+const GICD_IROUTER: usize = 0x6000;
+/// Peripheral and component ids, up to the end of the 64K frame.
+const GICD_ID: usize = 0xffd0;
+
+const GICD_IROUTER_IRM: u64 = 1 << 31;
+
+const GICR_CTLR: usize = 0x0000;
+const GICR_IIDR: usize = 0x0004;
+const GICR_TYPER: usize = 0x0008;
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+/// Affinity and processor number.
+const GICR_TYPER_IDS: u64 = 0xffff_ffff_00ff_ff00;
+/// Aff3.Aff2.Aff1.Aff0 of the frames of cpus outside the room.
+const GICR_TYPER_AFF_NONE: u64 = 0xffff_ffff;
+
+/// Emulate the distributor, and the redistributors on v3, for room `id`.
+pub fn register(id: RoomId, mmio: &mut MmioBus) -> HvResult {
+    let Some(info) = info() else {
+        return Ok(());
+    };
+    let gicd = info.gicd.start.as_usize();
+    mmio.register(
+        gicd..info.gicd.end.as_usize(),
+        Arc::new(VGicd {
+            room: id,
+            gicd,
+            version: info.version,
+            ctlr: AtomicU32::new(0),
+            routes: Mutex::new(BTreeMap::new()),
+        }),
+    )?;
+    if info.version == GicVersion::V3 {
+        for gicr in &info.gicr {
+            let base = gicr.start.as_usize();
+            mmio.register(
+                base..gicr.end.as_usize(),
+                Arc::new(VGicr { room: id, base }),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The cpus of room `id`, vcpu `n` runs on the `n`th.
+fn room_cpus(id: RoomId) -> Vec<CPUId> {
+    room::with(id, |room| room.cpus.clone()).unwrap_or_default()
+}
+
+fn this_vcpu() -> usize {
+    cpu_data().vcpu().map_or(0, |vcpu| vcpu.id)
+}
+
+struct VGicd {
+    room: RoomId,
+    gicd: usize,
+    version: GicVersion,
+    /// Only the guest sees its enable bits, the physical distributor stays on.
+    ctlr: AtomicU32,
+    /// Where the guest sent its SPIs: `GICD_ITARGETSR` bytes on v2, `GICD_IROUTER` on v3.
+    routes: Mutex<BTreeMap<u32, u64>>,
+}
+
+impl VGicd {
+    fn owns(&self, intid: u32) -> bool {
+        (SPI_BASE..nr_lines(self.gicd)).contains(&intid) && room::irq_owner(intid) == self.room
+    }
+
+    /// Bit `n` set if the room owns SPI `first + n`.
+    fn owned_mask(&self, first: u32) -> u32 {
+        (0..32)
+            .filter(|&n| self.owns(first + n))
+            .fold(0, |mask, n| mask | 1 << n)
+    }
+
+    fn default_route(&self) -> u64 {
+        match self.version {
+            GicVersion::V2 => 1,
+            GicVersion::V3 => vmpidr(0),
+        }
+    }
+
+    fn route_of(&self, intid: u32) -> u64 {
+        self.routes
+            .lock()
+            .get(&intid)
+            .copied()
+            .unwrap_or_else(|| self.default_route())
+    }
+
+    /// Route SPI `intid` to the cpu of the vcpu the guest chose, the boot one if it is not
+    /// one of the room.
+    fn apply_route(&self, intid: u32) {
+        let route = self.route_of(intid);
+        let cpus = room_cpus(self.room);
+        let target = match self.version {
+            GicVersion::V2 => cpus.get(route.trailing_zeros() as usize),
+            GicVersion::V3 if route & GICD_IROUTER_IRM != 0 => None,
+            GicVersion::V3 => vcpu_of(route).and_then(|n| cpus.get(n)),
+        };
+        if let Some(&cpu) = target.or(cpus.first()) {
+            route_irq(intid, cpu.into());
+        }
+    }
+
+    fn set_enable(&self, first: u32, bits: u32, enable: bool) {
+        for intid in (0..32).filter(|n| bits & 1 << n != 0).map(|n| first + n) {
+            // SGIs are always enabled.
+            if intid < 16 || (intid >= SPI_BASE && !self.owns(intid)) {
+                continue;
+            }
+            if !enable {
+                unforward_irq(intid);
+                continue;
+            }
+            if intid >= SPI_BASE {
+                self.apply_route(intid);
+            }
+            let _ = forward_irq(intid);
+        }
+    }
+
+    fn read_bits(&self, bank: usize, offset: usize) -> u32 {
+        let first = (offset - bank) as u32 * 8;
+        let phys = read32(self.gicd + offset);
+        match (bank, first) {
+            (GICD_ISENABLER | GICD_ICENABLER, 0) => phys | 0xffff,
+            (_, 0) => phys,
+            _ => phys & self.owned_mask(first),
+        }
+    }
+
+    fn write_bits(&self, bank: usize, offset: usize, value: u32) {
+        let first = (offset - bank) as u32 * 8;
+        match bank {
+            GICD_ISENABLER => self.set_enable(first, value, true),
+            GICD_ICENABLER => self.set_enable(first, value, false),
+            // Private pending and active states are the hypervisor's business.
+            GICD_ISPENDR | GICD_ICPENDR | GICD_ISACTIVER | GICD_ICACTIVER if first > 0 => {
+                let value = value & self.owned_mask(first);
+                if value != 0 {
+                    write_width(self.gicd + offset, 4, value as _);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Byte registers, one interrupt per byte.
+    fn read_bytes(&self, bank: usize, offset: usize, width: usize) -> u64 {
+        let mut value = 0;
+        for i in 0..width {
+            let intid = (offset - bank + i) as u32;
+            let byte = match bank {
+                GICD_ITARGETSR if intid < SPI_BASE => 1 << this_vcpu(),
+                GICD_ITARGETSR if self.owns(intid) => self.route_of(intid) as u8,
+                GICD_IPRIORITYR if intid < SPI_BASE || self.owns(intid) => {
+                    read_width(self.gicd + offset + i, 1) as u8
+                }
+                _ => 0,
+            };
+            value |= (byte as u64) << (i * 8);
+        }
+        value
+    }
+
+    fn write_bytes(&self, bank: usize, offset: usize, width: usize, value: u64) {
+        for i in 0..width {
+            let intid = (offset - bank + i) as u32;
+            if !self.owns(intid) {
+                continue;
+            }
+            let byte = (value >> (i * 8)) as u8;
+            match bank {
+                GICD_ITARGETSR => {
+                    self.routes.lock().insert(intid, byte as u64);
+                    self.apply_route(intid);
+                }
+                _ => write_width(self.gicd + offset + i, 1, byte as _),
+            }
+        }
+    }
+
+    /// `GICD_ICFGR`, two bits per interrupt.
+    fn cfg_mask(&self, offset: usize) -> u32 {
+        let first = (offset - GICD_ICFGR) as u32 * 4;
+        (0..16)
+            .filter(|&n| self.owns(first + n))
+            .fold(0, |mask, n| mask | 0b11 << (n * 2))
+    }
+
+    /// A guest SGI, to vcpus of the same room.
+    fn send_sgi(&self, value: u32) {
+        let sgi = value & 0xf;
+        let list = value >> 16 & 0xff;
+        let this = this_vcpu();
+        let cpus = room_cpus(self.room);
+        for (vcpu, &cpu) in cpus.iter().enumerate() {
+            let target = match value >> 24 & 0b11 {
+                0 => list & 1 << vcpu != 0,
+                1 => vcpu != this,
+                _ => vcpu == this,
+            };
+            if target {
+                vgic::inject(cpu, Virq::new(sgi));
+            }
+        }
+    }
+
+    fn irouter(&self, offset: usize) -> (u32, usize) {
+        let reg = offset - GICD_IROUTER;
+        ((reg / 8) as u32, reg % 8)
+    }
+}
+
+const BIT_BANKS: [usize; 7] = [
+    GICD_IGROUPR,
+    GICD_ISENABLER,
+    GICD_ICENABLER,
+    GICD_ISPENDR,
+    GICD_ICPENDR,
+    GICD_ISACTIVER,
+    GICD_ICACTIVER,
+];
+
+fn bank_of(offset: usize, banks: &[usize], size: usize) -> Option<usize> {
+    banks
+        .iter()
+        .copied()
+        .find(|&bank| (bank..bank + size).contains(&offset))
+}
+
+impl MmioHandler for VGicd {
+    fn read(&self, offset: usize, width: usize) -> HvResult<u64> {
+        let v3 = self.version == GicVersion::V3;
+        let value = match offset {
+            GICD_CTLR => self.ctlr.load(Ordering::Relaxed) as u64,
+            GICD_TYPER if !v3 => {
+                let cpus = room_cpus(self.room).len().max(1) as u32;
+                (read32(self.gicd + GICD_TYPER) & !(0b111 << 5) | (cpus - 1) << 5) as u64
+            }
+            GICD_TYPER | GICD_IIDR | GICD_TYPER2 => read_width(self.gicd + offset, width),
+            GICD_ID.. => read_width(self.gicd + offset, width),
+            o if bank_of(o, &BIT_BANKS, 0x80).is_some() => {
+                self.read_bits(bank_of(o, &BIT_BANKS, 0x80).unwrap(), o & !3) as u64
+            }
+            o if (GICD_IPRIORITYR..GICD_IPRIORITYR + 0x400).contains(&o) => {
+                self.read_bytes(GICD_IPRIORITYR, o, width)
+            }
+            o if !v3 && (GICD_ITARGETSR..GICD_ITARGETSR + 0x400).contains(&o) => {
+                self.read_bytes(GICD_ITARGETSR, o, width)
+            }
+            o if (GICD_ICFGR..GICD_ICFGR + 0x100).contains(&o) => {
+                let phys = read32(self.gicd + (o & !3));
+                match o & !3 {
+                    // SGIs and PPIs of the cpu
+                    GICD_ICFGR | 0x0c04 => phys as u64,
+                    _ => (phys & self.cfg_mask(o & !3)) as u64,
+                }
+            }
+            o if v3 && (GICD_IROUTER..GICD_IROUTER + 0x2000).contains(&o) => {
+                let (intid, half) = self.irouter(o);
+                match self.owns(intid) {
+                    true => self.route_of(intid) >> (half * 8),
+                    false => 0,
+                }
+            }
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&self, offset: usize, width: usize, value: u64) -> HvResult {
+        let v3 = self.version == GicVersion::V3;
+        match offset {
+            GICD_CTLR => self.ctlr.store(value as u32, Ordering::Relaxed),
+            GICD_SGIR if !v3 => self.send_sgi(value as u32),
+            o if bank_of(o, &BIT_BANKS, 0x80).is_some() => {
+                self.write_bits(bank_of(o, &BIT_BANKS, 0x80).unwrap(), o & !3, value as u32)
+            }
+            o if (GICD_IPRIORITYR..GICD_IPRIORITYR + 0x400).contains(&o) => {
+                self.write_bytes(GICD_IPRIORITYR, o, width, value)
+            }
+            o if !v3 && (GICD_ITARGETSR..GICD_ITARGETSR + 0x400).contains(&o) => {
+                self.write_bytes(GICD_ITARGETSR, o, width, value)
+            }
+            o if (GICD_ICFGR + 8..GICD_ICFGR + 0x100).contains(&o) => {
+                let mask = self.cfg_mask(o & !3);
+                if mask != 0 {
+                    let addr = self.gicd + (o & !3);
+                    let phys = read32(addr);
+                    write_width(addr, 4, (phys & !mask | value as u32 & mask) as _);
+                }
+            }
+            o if v3 && (GICD_IROUTER..GICD_IROUTER + 0x2000).contains(&o) => {
+                let (intid, half) = self.irouter(o);
+                if self.owns(intid) {
+                    let old = self.route_of(intid);
+                    let route = match (half, width) {
+                        (0, 8) => value,
+                        (0, _) => old & !0xffff_ffff | value & 0xffff_ffff,
+                        _ => old & 0xffff_ffff | value << 32,
+                    };
+                    self.routes.lock().insert(intid, route);
+                    self.apply_route(intid);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The redistributor frames of a GICR region. Every frame shows its `GICR_TYPER` so that
+/// the guest finds its own: the frame of the cpu running vcpu `n` has the affinity of
+/// [`vmpidr`]`(n)`, the others one no vcpu has and nothing else.
+struct VGicr {
+    room: RoomId,
+    base: usize,
+}
+
+impl VGicr {
+    fn stride(&self) -> usize {
+        match read_width(self.base + GICR_TYPER, 8) & GICR_TYPER_VLPIS {
+            0 => 0x2_0000,
+            _ => 0x4_0000,
+        }
+    }
+
+    /// The physical frame of `offset`, the offset in it and its vcpu and cpu if the room
+    /// has it.
+    fn frame(&self, offset: usize) -> (usize, usize, Option<(usize, CPUId)>) {
+        let stride = self.stride();
+        let frame = self.base + offset / stride * stride;
+        let aff = read_width(frame + GICR_TYPER, 8) >> 32;
+        let vcpu = room_cpus(self.room)
+            .into_iter()
+            .enumerate()
+            .find(|&(_, cpu)| v3::affinity(CPUHardId::from(cpu).raw() as u64) == aff);
+        (frame, offset % stride, vcpu)
+    }
+
+    /// `GICR_TYPER` of `frame` as the guest sees it.
+    fn typer(frame: usize, vcpu: Option<(usize, CPUId)>) -> u64 {
+        let typer = read_width(frame + GICR_TYPER, 8) & !GICR_TYPER_IDS;
+        match vcpu {
+            Some((n, _)) => typer | v3::affinity(vmpidr(n)) << 32 | (n as u64) << 8,
+            None => typer | GICR_TYPER_AFF_NONE << 32,
+        }
+    }
+}
+
+const GICR_ID_RANGE: Range<usize> = 0xffd0..0x1_0000;
+
+impl MmioHandler for VGicr {
+    fn read(&self, offset: usize, width: usize) -> HvResult<u64> {
+        let (frame, offset, vcpu) = self.frame(offset);
+        let cpu = vcpu.map(|(_, cpu)| cpu);
+        let value = match offset {
+            GICR_TYPER | 0x000c => {
+                let typer = Self::typer(frame, vcpu) >> ((offset - GICR_TYPER) * 8);
+                match width {
+                    8 => typer,
+                    _ => typer & 0xffff_ffff,
+                }
+            }
+            GICR_IIDR => read_width(frame + offset, width),
+            o if GICR_ID_RANGE.contains(&o) => read_width(frame + offset, width),
+            _ if cpu.is_none() => 0,
+            GICR_CTLR => 0,
+            o if o == GICR_SGI_BASE + GICD_IGROUPR => 0xffff_ffff,
+            o if o == GICR_SGI_BASE + GICD_ISENABLER || o == GICR_SGI_BASE + GICD_ICENABLER => {
+                read32(frame + o) as u64 | 0xffff
+            }
+            o if (GICR_SGI_BASE + GICD_IPRIORITYR..GICR_SGI_BASE + GICD_IPRIORITYR + 0x20)
+                .contains(&o)
+                || (GICR_SGI_BASE + GICD_ICFGR..GICR_SGI_BASE + GICD_ICFGR + 8).contains(&o) =>
+            {
+                read_width(frame + o, width)
+            }
+            _ => 0,
+        };
+        Ok(value)
+    }
+
+    fn write(&self, offset: usize, _width: usize, value: u64) -> HvResult {
+        let (_, offset, vcpu) = self.frame(offset);
+        // PPIs are enabled by the cpu that owns them.
+        if vcpu.map(|(_, cpu)| cpu) != Some(cpu_data().id) {
+            return Ok(());
+        }
+        let enable = match offset.checked_sub(GICR_SGI_BASE) {
+            Some(GICD_ISENABLER) => true,
+            Some(GICD_ICENABLER) => false,
+            _ => return Ok(()),
+        };
+        for intid in (16..32).filter(|n| value & 1 << n != 0) {
+            match enable {
+                true => {
+                    let _ = forward_irq(intid);
+                }
+                false => unforward_irq(intid),
+            }
+        }
+        Ok(())
+    }
+}
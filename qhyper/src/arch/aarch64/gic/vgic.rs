@@ -0,0 +1,299 @@
+//! Virtual cpu interface: interrupts are injected into the guest of a cpu through the list
+//! registers, GICH for v2 and ICH_*_EL2 for v3.
+//!
+//! A vcpu never leaves its cpu, so the list registers are not switched: they are cleared when
+//! a vcpu enters or parks and hold the guest state in between. Interrupts that do not fit wait
+//! in the queue of the cpu, which refills the list registers on every guest entry; an underflow
+//! maintenance interrupt makes the guest exit when they run low.
+//!
+//! On v3 the guest uses the system register interface, which `HCR_EL2.IMO` turns into the
+//! virtual one (ICV_*) backed by ICH_HCR_EL2, ICH_VMCR_EL2 and the list registers. Only its
+//! SGIs trap, see [`write_sgi1r`].
+
+use core::mem::take;
+
+use log::{trace, warn};
+use page_table_generic::{AccessSetting, CacheSetting};
+
+use crate::{
+    arch::vmpidr,
+    error::HvResult,
+    mem::guest::GuestPhysSpace,
+    percpu::{cpu_data, get_cpu_data, CPUId},
+    room,
+};
+
+use super::{
+    enable_irq, info, register_handler, send_sgi, v2, v3, version, GicVersion, PRIORITY_DEFAULT,
+    SGI_KICK,
+};
+
+const HCR_EN: u32 = 1 << 0;
+const HCR_UIE: u32 = 1 << 1;
+
+/// A virtual interrupt to make pending in a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Virq {
+    pub intid: u32,
+    pub priority: u8,
+    /// The physical interrupt deactivated when the guest deactivates this one.
+    pub hw: Option<u32>,
+}
+
+impl Virq {
+    pub fn new(intid: u32) -> Self {
+        Self {
+            intid,
+            priority: PRIORITY_DEFAULT,
+            hw: None,
+        }
+    }
+}
+
+/// A list register, whatever the GIC version.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lr {
+    pub intid: u32,
+    pub hw: Option<u32>,
+    pub priority: u8,
+    pub pending: bool,
+    pub active: bool,
+}
+
+macro_rules! dispatch {
+    ($f:ident($($arg:expr),*)) => {
+        match version() {
+            Some(GicVersion::V2) => v2::$f($($arg),*),
+            _ => v3::$f($($arg),*),
+        }
+    };
+}
+
+/// Whether the virtualization extensions were found, without them nothing is injected.
+fn present() -> bool {
+    match version() {
+        Some(GicVersion::V2) => info().is_some_and(|info| info.gich.is_some()),
+        Some(GicVersion::V3) => true,
+        None => false,
+    }
+}
+
+pub fn init() {
+    if let Some(irq) = info().and_then(|info| info.maintenance_irq) {
+        let _ = register_handler(irq, handle_maintenance);
+    }
+}
+
+pub fn init_cpu() {
+    if !present() {
+        warn!("GIC without virtualization extensions");
+        return;
+    }
+    reset();
+    if let Some(irq) = info().and_then(|info| info.maintenance_irq) {
+        enable_irq(irq);
+    }
+}
+
+/// Clear the list registers and the queue of the current cpu, for a new vcpu. The physical
+/// interrupts behind hardware ones were only EOIed, they are deactivated here since no guest
+/// will do it any more.
+pub fn reset() {
+    let virqs = take(&mut *cpu_data().virqs.lock());
+    for pintid in virqs.iter().filter_map(|virq| virq.hw) {
+        dispatch!(deactivate(pintid));
+    }
+    if !present() {
+        return;
+    }
+    let empty = dispatch!(empty_lrs());
+    for n in (0..dispatch!(lr_count())).filter(|n| empty & 1 << n == 0) {
+        if let Some(pintid) = dispatch!(read_lr(n)).hw {
+            dispatch!(deactivate(pintid));
+        }
+    }
+    dispatch!(reset_virtual());
+    dispatch!(set_hcr(HCR_EN));
+}
+
+/// On v2 the guest takes GICV in place of GICC, at the same IPA.
+pub fn map_cpu_interface(space: &mut GuestPhysSpace) -> HvResult {
+    let Some(info) = info().filter(|info| info.version == GicVersion::V2) else {
+        return Ok(());
+    };
+    let (Some(gicc), Some(gicv)) = (info.gicc, info.gicv) else {
+        return Ok(());
+    };
+    space.map(
+        gicc.start.as_usize(),
+        gicv.start.as_usize(),
+        gicc.size().min(gicv.size()),
+        AccessSetting::Read | AccessSetting::Write,
+        CacheSetting::Device,
+    )
+}
+
+/// Make `virq` pending in the guest running on `cpu`. It goes straight to a list register
+/// when that is the current cpu, otherwise `cpu` is kicked to take it from its queue.
+pub fn inject(cpu: CPUId, virq: Virq) {
+    let this = cpu_data();
+    if cpu == this.id && this.vcpu().is_some() && this.virqs.lock().is_empty() && write(virq) {
+        return;
+    }
+    {
+        let mut virqs = get_cpu_data(cpu).virqs.lock();
+        if !virqs.contains(&virq) {
+            virqs.push_back(virq);
+        }
+    }
+    if cpu != this.id {
+        let _ = send_sgi(cpu.into(), SGI_KICK);
+    }
+}
+
+/// A guest write of `ICC_SGI1R_EL1`, or of `ICC_ASGI1R_EL1`/`ICC_SGI0R_EL1` which are
+/// treated alike. The targets are vcpus of the same room, addressed by their [`vmpidr`].
+pub fn write_sgi1r(value: u64) {
+    let Some(vcpu) = cpu_data().vcpu() else {
+        return;
+    };
+    let sgi = (value >> 24 & 0xf) as u32;
+    let irm = value >> 40 & 1 == 1;
+    // Aff3.Aff2.Aff1 in MPIDR layout, Aff0 comes from the target list.
+    let aff = (value >> 48 & 0xff) << 32 | (value >> 32 & 0xff) << 16 | (value >> 16 & 0xff) << 8;
+    let aff0_base = (value >> 44 & 0xf) * 16;
+    let targets = value & 0xffff;
+
+    let this = cpu_data().id;
+    let cpus = room::with(vcpu.room, |room| room.cpus.clone()).unwrap_or_default();
+    for (n, cpu) in cpus.into_iter().enumerate() {
+        let target = match irm {
+            true => cpu != this,
+            false => {
+                let mpidr = vmpidr(n);
+                let aff0 = mpidr & 0xff;
+                mpidr & !0xff == aff
+                    && (aff0_base..aff0_base + 16).contains(&aff0)
+                    && targets & 1 << (aff0 - aff0_base) != 0
+            }
+        };
+        if target {
+            inject(cpu, Virq::new(sgi));
+        }
+    }
+}
+
+/// A physical interrupt routed to the guest of the current cpu, `false` without guest.
+pub(super) fn forward(intid: u32) -> bool {
+    if cpu_data().vcpu().is_none() {
+        return false;
+    }
+    inject(
+        cpu_data().id,
+        Virq {
+            hw: Some(intid),
+            ..Virq::new(intid)
+        },
+    );
+    true
+}
+
+/// Withdraw `intid` from the guest of the current cpu if it is still pending.
+pub fn clear(intid: u32) {
+    cpu_data().virqs.lock().retain(|virq| virq.intid != intid);
+    if !present() {
+        return;
+    }
+    let empty = dispatch!(empty_lrs());
+    for n in (0..dispatch!(lr_count())).filter(|n| empty & 1 << n == 0) {
+        let mut lr = dispatch!(read_lr(n));
+        if lr.intid == intid && lr.pending && lr.hw.is_none() {
+            lr.pending = false;
+            dispatch!(write_lr(n, lr));
+        }
+    }
+}
+
+/// Whether the guest of the current cpu has an interrupt waiting, queued or in a list
+/// register.
+pub fn pending() -> bool {
+    if !cpu_data().virqs.lock().is_empty() {
+        return true;
+    }
+    if !present() {
+        return false;
+    }
+    let empty = dispatch!(empty_lrs());
+    (0..dispatch!(lr_count()))
+        .filter(|n| empty & 1 << n == 0)
+        .any(|n| dispatch!(read_lr(n)).pending)
+}
+
+/// Move the queue of the current cpu into free list registers, before the guest resumes.
+pub fn flush() {
+    if !present() {
+        return;
+    }
+    let mut virqs = cpu_data().virqs.lock();
+    while let Some(&virq) = virqs.front() {
+        if !write(virq) {
+            break;
+        }
+        virqs.pop_front();
+    }
+    set_underflow(!virqs.is_empty());
+}
+
+/// Put `virq` in a list register, `false` if they are all taken.
+fn write(virq: Virq) -> bool {
+    let empty = dispatch!(empty_lrs());
+    let count = dispatch!(lr_count());
+
+    for n in (0..count).filter(|n| empty & 1 << n == 0) {
+        let mut lr = dispatch!(read_lr(n));
+        if lr.intid != virq.intid {
+            continue;
+        }
+        // An active hardware interrupt cannot fire again before the guest is done with it.
+        if !lr.pending && lr.hw.is_none() {
+            lr.pending = true;
+            dispatch!(write_lr(n, lr));
+        }
+        return true;
+    }
+
+    match (0..count).find(|n| empty & 1 << n != 0) {
+        Some(n) => {
+            trace!("inject {:?} in lr {}", virq, n);
+            dispatch!(write_lr(
+                n,
+                Lr {
+                    intid: virq.intid,
+                    hw: virq.hw,
+                    priority: virq.priority,
+                    pending: true,
+                    active: false,
+                }
+            ));
+            true
+        }
+        None => false,
+    }
+}
+
+fn set_underflow(enable: bool) {
+    let hcr = dispatch!(hcr());
+    let new = match enable {
+        true => hcr | HCR_UIE,
+        false => hcr & !HCR_UIE,
+    };
+    if new != hcr {
+        dispatch!(set_hcr(new));
+    }
+}
+
+/// The list registers ran low, the queue is looked at again when the guest resumes.
+fn handle_maintenance(_intid: u32) {
+    trace!("vgic maintenance");
+    set_underflow(false);
+}
@@ -0,0 +1,167 @@
+//! Decoder of the A64 load/store forms a guest may use on MMIO, for aborts without a valid
+//! syndrome.
+
+/// How the address is formed from the base register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Index {
+    /// `[base, offset]`
+    Offset,
+    /// `[base, offset]!`, the base is updated before the access.
+    Pre,
+    /// `[base], offset`, the base is updated after the access.
+    Post,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offset {
+    Imm(i64),
+    /// `Rm` extended by `extend` (the `option` field) then shifted left by `shift`.
+    Reg {
+        rm: usize,
+        extend: u32,
+        shift: u32,
+    },
+}
+
+/// A decoded integer load or store, of one register or a pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadStore {
+    pub is_load: bool,
+    /// Bytes per register.
+    pub size: usize,
+    /// Loads sign extend the value.
+    pub signed: bool,
+    /// The destination is a `W` register, 32 bits.
+    pub wreg: bool,
+    pub rt: usize,
+    pub rt2: Option<usize>,
+    /// 31 is `sp`.
+    pub rn: usize,
+    pub index: Index,
+    pub offset: Offset,
+}
+
+fn bits(insn: u32, hi: u32, lo: u32) -> u32 {
+    (insn >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value as i64) << shift) >> shift
+}
+
+/// Decode `insn`, `None` for anything else than integer loads and stores.
+pub fn decode(insn: u32) -> Option<LoadStore> {
+    // op0 x1x0: loads and stores, V (bit 26) set for SIMD&FP
+    if bits(insn, 27, 27) != 1 || bits(insn, 25, 25) != 0 || bits(insn, 26, 26) != 0 {
+        return None;
+    }
+    match bits(insn, 29, 28) {
+        0b11 => decode_single(insn),
+        0b10 => decode_pair(insn),
+        _ => None,
+    }
+}
+
+fn decode_single(insn: u32) -> Option<LoadStore> {
+    let size_log2 = bits(insn, 31, 30);
+    let opc = bits(insn, 23, 22);
+    let rt = bits(insn, 4, 0) as usize;
+    let rn = bits(insn, 9, 5) as usize;
+
+    let (is_load, signed, wreg) = match (size_log2, opc) {
+        (_, 0b00) => (false, false, size_log2 != 0b11),
+        (_, 0b01) => (true, false, size_log2 != 0b11),
+        // PRFM and the unallocated encoding
+        (0b11, _) => return None,
+        (0b10, 0b11) => return None,
+        (_, 0b10) => (true, true, false),
+        (_, _) => (true, true, true),
+    };
+    let size = 1 << size_log2;
+
+    let (index, offset) = if bits(insn, 24, 24) == 1 {
+        // unsigned immediate, scaled
+        let imm = (bits(insn, 21, 10) as i64) << size_log2;
+        (Index::Offset, Offset::Imm(imm))
+    } else if bits(insn, 21, 21) == 0 {
+        let imm = sign_extend(bits(insn, 20, 12), 9);
+        let index = match bits(insn, 11, 10) {
+            // LDUR/STUR, LDTR/STTR
+            0b00 | 0b10 => Index::Offset,
+            0b01 => Index::Post,
+            _ => Index::Pre,
+        };
+        (index, Offset::Imm(imm))
+    } else if bits(insn, 11, 10) == 0b10 {
+        let extend = bits(insn, 15, 13);
+        if extend & 0b010 == 0 {
+            return None;
+        }
+        let shift = match bits(insn, 12, 12) {
+            1 => size_log2,
+            _ => 0,
+        };
+        let rm = bits(insn, 20, 16) as usize;
+        (Index::Offset, Offset::Reg { rm, extend, shift })
+    } else {
+        // atomics
+        return None;
+    };
+
+    Some(LoadStore {
+        is_load,
+        size,
+        signed,
+        wreg,
+        rt,
+        rt2: None,
+        rn,
+        index,
+        offset,
+    })
+}
+
+fn decode_pair(insn: u32) -> Option<LoadStore> {
+    let opc = bits(insn, 31, 30);
+    let is_load = bits(insn, 22, 22) == 1;
+    let (size_log2, signed) = match opc {
+        0b00 => (2, false),
+        // LDPSW
+        0b01 if is_load => (2, true),
+        0b10 => (3, false),
+        _ => return None,
+    };
+    let index = match bits(insn, 24, 23) {
+        0b01 => Index::Post,
+        0b11 => Index::Pre,
+        // LDNP/STNP and the signed offset form
+        _ => Index::Offset,
+    };
+    let imm = sign_extend(bits(insn, 21, 15), 7) << size_log2;
+
+    Some(LoadStore {
+        is_load,
+        size: 1 << size_log2,
+        signed,
+        wreg: opc == 0b00,
+        rt: bits(insn, 4, 0) as usize,
+        rt2: Some(bits(insn, 14, 10) as usize),
+        rn: bits(insn, 9, 5) as usize,
+        index,
+        offset: Offset::Imm(imm),
+    })
+}
+
+/// The offset `Rm` gives once extended and shifted.
+pub fn extend_reg(value: u64, extend: u32, shift: u32) -> i64 {
+    let value = match extend {
+        // UXTW
+        0b010 => value as u32 as i64,
+        // SXTW
+        0b110 => value as u32 as i32 as i64,
+        // LSL/UXTX, SXTX
+        _ => value as i64,
+    };
+    value << shift
+}
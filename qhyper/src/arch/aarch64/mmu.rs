@@ -1,7 +1,7 @@
 use core::{
     arch::asm,
     ptr::slice_from_raw_parts,
-    sync::atomic::{fence, Ordering},
+    sync::atomic::{fence, AtomicUsize, Ordering},
 };
 
 use aarch64_cpu::{
@@ -14,7 +14,10 @@ use page_table_arm::*;
 use page_table_generic::*;
 
 use crate::{
-    arch::boot::rust_main,
+    arch::{
+        boot::{rust_main, secondary_rust_main},
+        cache,
+    },
     debug::*,
     mem::{
         self, boot_stack, boot_stack_space, debug_space,
@@ -25,6 +28,9 @@ use crate::{
 
 pub type TableRef<'a> = PageTableRef<'a, PageTableImpl>;
 
+/// Page table used by secondary cpus, read before their MMU is enabled.
+static SECONDARY_TABLE: AtomicUsize = AtomicUsize::new(0);
+
 pub fn get_table() -> TableRef<'static> {
     PageTableRef::<PageTableImpl>::from_addr(
         (TTBR0_EL2.read(TTBR0_EL2::BADDR) << 1) as usize,
@@ -116,15 +122,7 @@ pub fn init() -> ! {
             }
         }
 
-        // Enable page size = 4K, vaddr size = 48 bits, paddr size = 40 bits.
-        TCR_EL2.write(
-            TCR_EL2::PS::Bits_48
-                + TCR_EL2::TG0::KiB_4
-                + TCR_EL2::SH0::Inner
-                + TCR_EL2::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-                + TCR_EL2::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-                + TCR_EL2::T0SZ.val(16),
-        );
+        tcr_el2_apply();
 
         TTBR0_EL2.set(table.paddr() as _);
 
@@ -150,6 +148,44 @@ pub fn init() -> ! {
     }
 }
 
+/// Publish the current page table for secondary cpus.
+///
+/// They read it with the MMU and caches off, so it is cleaned to the point of coherency.
+pub fn share_table_with_secondary() {
+    SECONDARY_TABLE.store(get_table().paddr(), Ordering::SeqCst);
+    cache::dcache_clean_invalidate(
+        &SECONDARY_TABLE as *const _ as usize,
+        size_of::<AtomicUsize>(),
+    );
+}
+
+/// Enable the MMU of a secondary cpu with the shared page table, then jump to the kernel
+/// virtual address space on `stack_top`.
+pub fn init_secondary(stack_top: usize) -> ! {
+    mair_el2_apply();
+    tcr_el2_apply();
+
+    TTBR0_EL2.set(SECONDARY_TABLE.load(Ordering::SeqCst) as _);
+
+    unsafe {
+        barrier::isb(barrier::SY);
+        asm!("tlbi alle2; dsb sy; isb");
+
+        SCTLR_EL2.modify(SCTLR_EL2::M::Enable + SCTLR_EL2::C::Cacheable + SCTLR_EL2::I::Cacheable);
+        isb(SY);
+
+        asm!(
+            "MOV      sp,  {stack}",
+            "LDR      x8,  ={entry}",
+            "BLR      x8",
+            "B       .",
+            stack = in(reg) stack_top,
+            entry = sym secondary_rust_main,
+            options(nomem, nostack,noreturn)
+        )
+    }
+}
+
 fn map_space(table: &mut PageTableRef<PageTableImpl>, space: &Space, access: &mut TableAlloc) {
     let paddr = space.phys.start.as_usize();
     let vaddr = space.virt().start.as_ptr();
@@ -200,6 +236,18 @@ fn map_direct(
     );
 }
 
+fn tcr_el2_apply() {
+    // Enable page size = 4K, vaddr size = 48 bits, paddr size = 40 bits.
+    TCR_EL2.write(
+        TCR_EL2::PS::Bits_48
+            + TCR_EL2::TG0::KiB_4
+            + TCR_EL2::SH0::Inner
+            + TCR_EL2::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL2::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL2::T0SZ.val(16),
+    );
+}
+
 fn mair_el2_apply() {
     let attr0 = MAIR_EL2::Attr0_Device::nonGathering_nonReordering_noEarlyWriteAck;
     // Normal memory
@@ -320,3 +368,193 @@ impl PTEArch for PageTableImpl {
         }
     }
 }
+
+pub type Stage2TableRef<'a> = PageTableRef<'a, Stage2PageTableImpl>;
+
+/// Guest physical addresses are 39 bits wide, translation starts at level 1.
+pub const STAGE2_IPA_BITS: usize = 39;
+
+/// Program VTCR_EL2 and VTTBR_EL2 so that EL1 accesses are translated by `table` tagged with `vmid`.
+pub fn activate_stage2(vmid: usize, table: &Stage2TableRef<'_>) {
+    let parange = ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange).min(0b101);
+
+    VTCR_EL2.write(
+        VTCR_EL2::RES1::SET
+            + VTCR_EL2::PS.val(parange)
+            + VTCR_EL2::TG0::Granule4KB
+            + VTCR_EL2::SH0::Inner
+            + VTCR_EL2::ORGN0::NormalWBRAWA
+            + VTCR_EL2::IRGN0::NormalWBRAWA
+            + VTCR_EL2::SL0::Granule4KBLevel1
+            + VTCR_EL2::T0SZ.val((64 - STAGE2_IPA_BITS) as _),
+    );
+    VTTBR_EL2
+        .write(VTTBR_EL2::VMID.val(vmid as _) + VTTBR_EL2::BADDR.val(table.paddr() as u64 >> 1));
+    isb(SY);
+}
+
+/// Invalidate stage-2 TLB entries of `vmid`, for the page at `ipa` or the whole VM if `None`.
+///
+/// TLBI by IPA only acts on the VMID in VTTBR_EL2, so the target VMID is switched in and out.
+pub fn flush_guest_tlb(vmid: usize, table: &Stage2TableRef<'_>, ipa: Option<usize>) {
+    let old = VTTBR_EL2.get();
+    VTTBR_EL2
+        .write(VTTBR_EL2::VMID.val(vmid as _) + VTTBR_EL2::BADDR.val(table.paddr() as u64 >> 1));
+    isb(SY);
+    unsafe {
+        match ipa {
+            Some(ipa) => asm!(
+                "dsb ishst",
+                "tlbi ipas2e1is, {}",
+                "dsb ish",
+                "tlbi vmalle1is",
+                "dsb ish",
+                "isb",
+                in(reg) ipa >> 12
+            ),
+            None => asm!("dsb ishst", "tlbi vmalls12e1is", "dsb ish", "isb"),
+        }
+    }
+    VTTBR_EL2.set(old);
+    isb(SY);
+}
+
+/// Translate a virtual address of the guest running on this cpu to an IPA, with its own
+/// stage-1 tables, `None` if the guest could not access it.
+///
+/// The guest EL1 registers must still be loaded, PAR_EL1 is clobbered.
+pub fn guest_va_to_ipa(va: usize, write: bool) -> Option<usize> {
+    unsafe {
+        match write {
+            true => asm!("at s1e1w, {}", in(reg) va),
+            false => asm!("at s1e1r, {}", in(reg) va),
+        }
+    }
+    isb(SY);
+    let par = PAR_EL1.get();
+    if par & 1 != 0 {
+        return None;
+    }
+    Some((par as usize & 0xf_ffff_ffff_f000) | (va & 0xfff))
+}
+
+bitflags::bitflags! {
+    /// Attribute fields of stage-2 VMSAv8-64 descriptors.
+    #[derive(Debug, Clone, Copy)]
+    pub struct S2PTEFlags: u64 {
+        /// Whether the descriptor is valid.
+        const VALID =       1 << 0;
+        /// Table or page descriptor (not a 2M, 1G block).
+        const NON_BLOCK =   1 << 1;
+        /// MemAttr[3:0], Device-nGnRE.
+        const ATTR_DEVICE = 0b0001 << 2;
+        /// MemAttr[3:0], Normal Inner/Outer Non-cacheable.
+        const ATTR_NONCACHE = 0b0101 << 2;
+        /// MemAttr[3:0], Normal Inner/Outer Write-Back.
+        const ATTR_NORMAL = 0b1111 << 2;
+        /// S2AP[0], readable from EL1/EL0.
+        const S2AP_R =      1 << 6;
+        /// S2AP[1], writable from EL1/EL0.
+        const S2AP_W =      1 << 7;
+        /// Shareability: Inner Shareable.
+        const INNER =       0b11 << 8;
+        /// The Access flag.
+        const AF =          1 << 10;
+        /// Execute-never.
+        const XN =          1 << 54;
+    }
+}
+
+impl S2PTEFlags {
+    const ATTR_MASK: u64 = 0b1111 << 2;
+    const PHYS_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+}
+
+#[derive(Clone, Copy)]
+pub struct Stage2PageTableImpl;
+
+impl PTEArch for Stage2PageTableImpl {
+    fn page_size() -> usize {
+        0x1000
+    }
+
+    fn level() -> usize {
+        3
+    }
+
+    fn new_pte(config: PTEGeneric) -> usize {
+        let mut flags = S2PTEFlags::empty();
+
+        if config.is_valid {
+            flags |= S2PTEFlags::VALID;
+        }
+
+        if !config.is_block {
+            flags |= S2PTEFlags::NON_BLOCK;
+        }
+
+        flags |= match config.setting.cache_setting {
+            CacheSetting::Normal => S2PTEFlags::ATTR_NORMAL | S2PTEFlags::INNER,
+            CacheSetting::Device => S2PTEFlags::ATTR_DEVICE,
+            CacheSetting::NonCache => S2PTEFlags::ATTR_NONCACHE | S2PTEFlags::INNER,
+        };
+
+        let privilege = &config.setting.privilege_access;
+
+        if privilege.readable() {
+            flags |= S2PTEFlags::S2AP_R | S2PTEFlags::AF;
+        }
+
+        if privilege.writable() {
+            flags |= S2PTEFlags::S2AP_W | S2PTEFlags::AF;
+        }
+
+        if !privilege.executable() {
+            flags |= S2PTEFlags::XN;
+        }
+
+        ((config.paddr as u64 & S2PTEFlags::PHYS_ADDR_MASK) | flags.bits()) as _
+    }
+
+    fn read_pte(pte: usize) -> PTEGeneric {
+        let pte = pte as u64;
+        let paddr = (pte & S2PTEFlags::PHYS_ADDR_MASK) as usize;
+        let flags = S2PTEFlags::from_bits_truncate(pte);
+        let is_valid = flags.contains(S2PTEFlags::VALID);
+        let is_block = !flags.contains(S2PTEFlags::NON_BLOCK);
+        let mut privilege_access = AccessSetting::empty();
+        let mut cache_setting = CacheSetting::Normal;
+
+        if is_valid {
+            cache_setting = match pte & S2PTEFlags::ATTR_MASK {
+                a if a == S2PTEFlags::ATTR_NORMAL.bits() => CacheSetting::Normal,
+                a if a == S2PTEFlags::ATTR_NONCACHE.bits() => CacheSetting::NonCache,
+                _ => CacheSetting::Device,
+            };
+
+            if flags.contains(S2PTEFlags::S2AP_R) {
+                privilege_access |= AccessSetting::Read;
+            }
+
+            if flags.contains(S2PTEFlags::S2AP_W) {
+                privilege_access |= AccessSetting::Write;
+            }
+
+            if !flags.contains(S2PTEFlags::XN) {
+                privilege_access |= AccessSetting::Execute;
+            }
+        }
+
+        PTEGeneric {
+            paddr,
+            is_block,
+            is_valid,
+            setting: PTESetting {
+                is_global: true,
+                privilege_access,
+                user_access: privilege_access,
+                cache_setting,
+            },
+        }
+    }
+}
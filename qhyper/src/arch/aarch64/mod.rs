@@ -1,21 +1,59 @@
 mod boot;
-mod cache;
+pub mod cache;
+#[macro_use]
 mod cpu;
+pub mod gic;
+mod insn;
 pub mod mmu;
+mod psci;
+pub mod timer;
 mod trap;
+mod vpsci;
+mod vsmccc;
 
-use core::hint::spin_loop;
+// The FP/SIMD registers are left to the guests, they are neither saved nor restored on exits.
+#[cfg(target_feature = "neon")]
+compile_error!("build the hypervisor without FP/SIMD, e.g. for aarch64-unknown-none-softfloat");
 
-use aarch64_cpu::registers::*;
-use log::error;
+use aarch64_cpu::{asm::wfi, registers::*};
+pub use boot::cpu_on;
+pub use cpu::{vcpu_of, vmpidr, VCpu};
+use log::{error, info};
 pub use trap::install_trap_vector;
 
 use crate::percpu::CPUHardId;
 
+/// Power off the machine through PSCI `SYSTEM_OFF`.
 pub fn shutdown() -> ! {
-    error!("shutdown unimplemented");
+    info!("shutdown");
+    if let Err(e) = psci::system_off() {
+        error!("psci system_off failed: {:?}", e);
+    }
+    halt()
+}
+
+/// Reset the machine through PSCI `SYSTEM_RESET`.
+pub fn reboot() -> ! {
+    info!("reboot");
+    if let Err(e) = psci::system_reset() {
+        error!("psci system_reset failed: {:?}", e);
+    }
+    halt()
+}
+
+/// Sleep until an interrupt comes and serve it. Interrupts are masked in the hypervisor
+/// otherwise, so nothing it runs is interrupted with a lock held.
+pub fn wait_for_irq() {
+    DAIF.modify(DAIF::I::Unmasked);
+    wfi();
+    DAIF.modify(DAIF::I::Masked);
+}
+
+/// Stop the current cpu forever with interrupts masked.
+pub fn halt() -> ! {
+    DAIF.write(DAIF::D::Masked + DAIF::A::Masked + DAIF::I::Masked + DAIF::F::Masked);
     loop {
-        spin_loop();
+        wfi();
     }
 }
 
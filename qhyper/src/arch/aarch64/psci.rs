@@ -0,0 +1,20 @@
+//! PSCI calls of the hypervisor itself. They always go through `smc`: the `method` of the
+//! fdt `/psci` node is the conduit of the kernel the fdt was written for, and an `hvc` from
+//! EL2 would only trap back here.
+
+use smccc::{
+    psci::{self, Error},
+    Smc,
+};
+
+pub fn cpu_on(target_cpu: u64, entry_point_address: u64, context_id: u64) -> Result<(), Error> {
+    psci::cpu_on::<Smc>(target_cpu, entry_point_address, context_id)
+}
+
+pub fn system_off() -> Result<(), Error> {
+    psci::system_off::<Smc>()
+}
+
+pub fn system_reset() -> Result<(), Error> {
+    psci::system_reset::<Smc>()
+}
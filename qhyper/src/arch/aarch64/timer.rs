@@ -0,0 +1,59 @@
+//! The generic timer of the cpu.
+
+use aarch64_cpu::registers::*;
+
+/// The physical count, the same on every cpu.
+pub fn counter() -> u64 {
+    CNTPCT_EL0.get()
+}
+
+/// Counter ticks per second, set up by the firmware.
+pub fn frequency() -> u64 {
+    CNTFRQ_EL0.get()
+}
+
+/// The EL2 physical timer PPI.
+pub const HYP_TIMER_IRQ: u32 = 26;
+
+const CNTHP_CTL_ENABLE: u64 = 1 << 0;
+
+/// Raise [`HYP_TIMER_IRQ`] on this cpu once the counter reaches `deadline`.
+pub fn set_hyp_deadline(deadline: u64) {
+    write_sysreg!(cnthp_cval_el2, deadline);
+    write_sysreg!(cnthp_ctl_el2, CNTHP_CTL_ENABLE);
+}
+
+pub fn stop_hyp() {
+    write_sysreg!(cnthp_ctl_el2, 0u64);
+}
+
+/// The guest virtual timer PPI.
+pub const VIRT_TIMER_IRQ: u32 = 27;
+
+const CNTV_CTL_ENABLE: u64 = 1 << 0;
+const CNTV_CTL_IMASK: u64 = 1 << 1;
+const CNTHCTL_EL1PCTEN: u64 = 1 << 0;
+
+/// Guests read the physical counter but their accesses to the EL1 physical timer trap, the
+/// virtual timer is theirs.
+pub fn init_cpu() {
+    write_sysreg!(cnthctl_el2, CNTHCTL_EL1PCTEN);
+    write_sysreg!(cntvoff_el2, 0u64);
+    stop_virtual();
+    stop_hyp();
+}
+
+/// The virtual count of the guest is the physical one minus `offset`.
+pub fn set_virtual_offset(offset: u64) {
+    write_sysreg!(cntvoff_el2, offset);
+}
+
+pub fn stop_virtual() {
+    write_sysreg!(cntv_ctl_el0, 0u64);
+}
+
+/// The physical count at which a virtual timer set up with `ctl` and `cval` fires, `None` if
+/// it is disabled or masked.
+pub fn virtual_deadline(ctl: u64, cval: u64, offset: u64) -> Option<u64> {
+    (ctl & (CNTV_CTL_ENABLE | CNTV_CTL_IMASK) == CNTV_CTL_ENABLE).then(|| cval.wrapping_add(offset))
+}
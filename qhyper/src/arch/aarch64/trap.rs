@@ -1,11 +1,27 @@
 use core::arch::global_asm;
 
+use alloc::format;
+
 use aarch64_cpu::registers::*;
-use log::{error, trace};
+use log::{debug, error, trace};
 
-use crate::{arch::shutdown, println};
+use crate::{
+    arch::shutdown,
+    device::mmio::{self, MmioAccess},
+    error::HvResult,
+    hv_err, hv_result_err,
+    hypercall::HyperCall,
+    percpu::cpu_data,
+    println, room,
+};
 
-use super::cpu::GeneralRegisters;
+use super::{
+    cpu::{GeneralRegisters, VCpu},
+    gic,
+    insn::{self, Index, Offset},
+    mmu::guest_va_to_ipa,
+    vsmccc,
+};
 
 global_asm!(
     include_str!("./trap.S"),
@@ -27,8 +43,9 @@ pub fn handle_exit(regs: &mut GeneralRegisters) -> ! {
     // let _cpu_id = mpidr_to_cpuid(mpidr);
     trace!("cpu exit, exit_reson:{:#x?}", regs.exit_reason);
     match regs.exit_reason as u64 {
-        ExceptionType::EXIT_REASON_EL1_IRQ => irqchip_handle_irq_el1(),
-        // ExceptionType::EXIT_REASON_EL1_ABORT => arch_handle_trap_el1(regs),
+        ExceptionType::EXIT_REASON_EL1_IRQ | ExceptionType::EXIT_REASON_EL1_ABORT => {
+            handle_guest_exit(regs)
+        }
         ExceptionType::EXIT_REASON_EL2_ABORT => handle_trap_el2(regs),
         ExceptionType::EXIT_REASON_EL2_IRQ => irqchip_handle_irq_el2(),
         _ => arch_dump_exit(regs.exit_reason),
@@ -36,6 +53,237 @@ pub fn handle_exit(regs: &mut GeneralRegisters) -> ! {
     unsafe { vmreturn(regs as *const _ as usize) }
 }
 
+/// An exit from the guest running on this cpu, the vcpu state is saved before handling
+/// and the guest is resumed afterwards.
+fn handle_guest_exit(regs: &mut GeneralRegisters) -> ! {
+    let vcpu = cpu_data()
+        .vcpu()
+        .expect("guest exit without a vcpu on this cpu");
+    vcpu.save(regs);
+
+    match regs.exit_reason {
+        ExceptionType::EXIT_REASON_EL1_IRQ => irqchip_handle_irq_el1(),
+        _ => arch_handle_trap_el1(vcpu),
+    }
+
+    // A request to a cpu running a guest parks it first, `Run` then restarts the room.
+    if cpu_data().peek_request().is_some() {
+        room::park();
+    }
+    vcpu.run()
+}
+
+fn arch_handle_trap_el1(vcpu: &mut VCpu) {
+    match ESR_EL2.read_as_enum(ESR_EL2::EC) {
+        Some(ESR_EL2::EC::Value::HVC64) => handle_hypercall(vcpu),
+        Some(ESR_EL2::EC::Value::SMC64) => {
+            handle_hypercall(vcpu);
+            // A trapped SMC returns to itself, unlike HVC.
+            vcpu.elr += 4;
+        }
+        Some(ESR_EL2::EC::Value::TrappedWFIorWFE) => {
+            vcpu.elr += 4;
+            vcpu.wait();
+        }
+        Some(ESR_EL2::EC::Value::TrappedMsrMrs) => {
+            if let Err(e) = handle_sysreg(vcpu) {
+                error!("System register trap at {:#x?}: {:?}", vcpu.elr, e);
+                room::fail(vcpu.room);
+            }
+        }
+        Some(ESR_EL2::EC::Value::DataAbortLowerEL) => {
+            if let Err(e) = handle_data_abort(vcpu) {
+                error!("Data abort at {:#x?}: {:?}", vcpu.elr, e);
+                room::fail(vcpu.room);
+            }
+        }
+        _ => {
+            error!(
+                "Unhandled EL1 trap: EC={:#x?}, ESR_EL2: {:#x?}, ELR_EL2: {:#x?}",
+                ESR_EL2.read(ESR_EL2::EC),
+                ESR_EL2.get(),
+                vcpu.elr
+            );
+            room::fail(vcpu.room);
+        }
+    }
+}
+
+/// A trapped `msr`/`mrs`: the GICv3 SGI registers and the EL1 physical timer, which guests
+/// do not get.
+fn handle_sysreg(vcpu: &mut VCpu) -> HvResult {
+    let iss = ESR_EL2.read(ESR_EL2::ISS);
+    let op0 = iss >> 20 & 0b11;
+    let op2 = iss >> 17 & 0b111;
+    let op1 = iss >> 14 & 0b111;
+    let crn = iss >> 10 & 0xf;
+    let rt = (iss >> 5 & 0x1f) as usize;
+    let crm = iss >> 1 & 0xf;
+    let is_read = iss & 1 == 1;
+
+    match (op0, op1, crn, crm, op2) {
+        // ICC_SGI1R_EL1, ICC_ASGI1R_EL1, ICC_SGI0R_EL1: write only
+        (3, 0, 12, 11, 5..=7) => match is_read {
+            true => vcpu.set_reg(rt, 0),
+            false => gic::vgic::write_sgi1r(vcpu.reg(rt)),
+        },
+        // CNTP_TVAL_EL0, CNTP_CTL_EL0, CNTP_CVAL_EL0: RAZ/WI
+        (3, 3, 14, 2, 0..=2) => {
+            if is_read {
+                vcpu.set_reg(rt, 0);
+            }
+        }
+        _ => {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "unhandled {} s{}_{}_c{}_c{}_{}",
+                    if is_read { "mrs" } else { "msr" },
+                    op0,
+                    op1,
+                    crn,
+                    crm,
+                    op2
+                )
+            )
+        }
+    }
+    vcpu.elr += 4;
+    Ok(())
+}
+
+/// A stage-2 fault on an IPA without mapping, emulated by the MMIO bus of the room.
+fn handle_data_abort(vcpu: &mut VCpu) -> HvResult {
+    let iss = ESR_EL2.read(ESR_EL2::ISS);
+    let isv = iss >> 24 & 1 == 1;
+    let sas = iss >> 22 & 0b11;
+    let sse = iss >> 21 & 1 == 1;
+    let srt = (iss >> 16 & 0x1f) as usize;
+    let sf = iss >> 15 & 1 == 1;
+    let wnr = iss >> 6 & 1 == 1;
+    let ipa = (HPFAR_EL2.read(HPFAR_EL2::FIPA) << 12) as usize | (FAR_EL2.get() & 0xfff) as usize;
+
+    if !isv {
+        return emulate_load_store(vcpu);
+    }
+
+    let width = 1 << sas;
+    let mut access = MmioAccess {
+        address: ipa,
+        width,
+        is_write: wnr,
+        value: if wnr {
+            vcpu.reg(srt) & width_mask(width)
+        } else {
+            0
+        },
+    };
+    mmio::handle(vcpu.room, &mut access)?;
+
+    if !wnr {
+        vcpu.set_reg(srt, load_value(access.value, width, sse, sf));
+    }
+    vcpu.elr += if ESR_EL2.is_set(ESR_EL2::IL) { 4 } else { 2 };
+    Ok(())
+}
+
+/// An abort without syndrome (pairs, writeback, ...): the instruction is fetched from the
+/// guest and emulated against the MMIO bus.
+fn emulate_load_store(vcpu: &mut VCpu) -> HvResult {
+    let pc = vcpu.elr as usize;
+    let pc_ipa = guest_va_to_ipa(pc, false)
+        .ok_or_else(|| hv_err!(EFAULT, format!("pc {:#x} not mapped", pc)))?;
+    let mut raw = [0u8; 4];
+    room::with(vcpu.room, |room| {
+        room.space.copy_from_guest(pc_ipa, &mut raw)
+    })??;
+    let raw = u32::from_le_bytes(raw);
+    let op = insn::decode(raw)
+        .ok_or_else(|| hv_err!(EINVAL, format!("cannot emulate {:#010x} at {:#x}", raw, pc)))?;
+    trace!("emulate {:#010x}: {:x?}", raw, op);
+
+    let base = match op.rn {
+        31 => vcpu.sp(),
+        rn => vcpu.reg(rn),
+    };
+    let offset = match op.offset {
+        Offset::Imm(imm) => imm,
+        Offset::Reg { rm, extend, shift } => insn::extend_reg(vcpu.reg(rm), extend, shift),
+    };
+    let updated = base.wrapping_add(offset as u64);
+    let address = match op.index {
+        Index::Post => base,
+        _ => updated,
+    } as usize;
+
+    for (i, rt) in [Some(op.rt), op.rt2].into_iter().flatten().enumerate() {
+        let va = address + i * op.size;
+        let ipa = guest_va_to_ipa(va, !op.is_load)
+            .ok_or_else(|| hv_err!(EFAULT, format!("va {:#x} not mapped", va)))?;
+        let mut access = MmioAccess {
+            address: ipa,
+            width: op.size,
+            is_write: !op.is_load,
+            value: if op.is_load {
+                0
+            } else {
+                vcpu.reg(rt) & width_mask(op.size)
+            },
+        };
+        mmio::handle(vcpu.room, &mut access)?;
+        if op.is_load {
+            vcpu.set_reg(rt, load_value(access.value, op.size, op.signed, !op.wreg));
+        }
+    }
+
+    if op.index != Index::Offset {
+        match op.rn {
+            31 => vcpu.set_sp(updated),
+            rn => vcpu.set_reg(rn, updated),
+        }
+    }
+    vcpu.elr += 4;
+    Ok(())
+}
+
+/// The register value of a `width` bytes load, sign extended if `signed`, to 64 bits if
+/// `sf` or 32 otherwise.
+fn load_value(value: u64, width: usize, signed: bool, sf: bool) -> u64 {
+    let mut value = value & width_mask(width);
+    if signed && width < 8 && value >> (width * 8 - 1) & 1 == 1 {
+        value |= !width_mask(width);
+    }
+    if !sf {
+        value &= width_mask(4);
+    }
+    value
+}
+
+fn width_mask(width: usize) -> u64 {
+    match width {
+        8 => u64::MAX,
+        _ => (1 << (width * 8)) - 1,
+    }
+}
+
+/// SMCCC calls go to [`vsmccc`]. Otherwise `x0` is the hypercall id, `x1` and `x2` its
+/// arguments, the result or the negative errno goes back in `x0`.
+fn handle_hypercall(vcpu: &mut VCpu) {
+    if vsmccc::is_smccc(vcpu.regs.usr[0]) {
+        vsmccc::call(vcpu);
+        return;
+    }
+    let [id, arg0, arg1] = [vcpu.regs.usr[0], vcpu.regs.usr[1], vcpu.regs.usr[2]];
+    let ret = match HyperCall::new(cpu_data()).hypercall(id, arg0, arg1) {
+        Ok(ret) => ret as u64,
+        Err(e) => {
+            debug!("hypercall {:#x} failed: {:?}", id, e);
+            e.code() as u64
+        }
+    };
+    vcpu.regs.usr[0] = ret;
+}
+
 #[allow(dead_code)]
 #[allow(non_snake_case)]
 #[allow(non_upper_case_globals)]
@@ -54,12 +302,12 @@ fn arch_dump_exit(reason: u64) {
 
 fn irqchip_handle_irq_el1() {
     trace!("irq from el1");
-    // gic_handle_irq();
+    gic::handle_irq();
 }
 
 fn irqchip_handle_irq_el2() {
-    error!("irq not handle from el2");
-    shutdown();
+    trace!("irq from el2");
+    gic::handle_irq();
 }
 
 #[naked]
@@ -93,6 +341,30 @@ pub unsafe extern "C" fn vmreturn(_gu_regs: usize) -> ! {
     }
 }
 
+/// Copy `regs` to the top of the cpu stack as a trap frame and `eret` with it.
+#[naked]
+pub unsafe extern "C" fn enter_guest(_regs: &GeneralRegisters, _stack_top: usize) -> ! {
+    unsafe {
+        core::arch::naked_asm!(
+            "
+        /* x0: guest registers, x1: stack top */
+        sub     sp, x1, #{frame}
+        mov     x2, sp
+        mov     x3, #{frame}
+    1:
+        ldp     x4, x5, [x0], #16
+        stp     x4, x5, [x2], #16
+        subs    x3, x3, #16
+        b.ne    1b
+        mov     x0, sp
+        b       {vmreturn}
+    ",
+            frame = const core::mem::size_of::<GeneralRegisters>(),
+            vmreturn = sym vmreturn,
+        )
+    }
+}
+
 fn handle_trap_el2(_regs: &mut GeneralRegisters) {
     let elr = ELR_EL2.get();
     let esr = ESR_EL2.get();
@@ -0,0 +1,141 @@
+//! PSCI 1.1 for guests, against the vcpus of their room. A vcpu is addressed by its
+//! [`vmpidr`](super::vmpidr). `SYSTEM_OFF` and `SYSTEM_RESET` stop or restart the room of
+//! the caller, the root room included, never the machine: the other rooms go on.
+
+use log::{debug, info};
+use smccc::{
+    arch::SMCCC_VERSION,
+    psci::{
+        error::{ALREADY_ON, INVALID_PARAMETERS, NOT_SUPPORTED, ON_PENDING},
+        *,
+    },
+};
+
+use crate::{
+    percpu::{cpu_data, get_cpu_data, CPUId, CpuRequest},
+    room,
+};
+
+use super::{vcpu_of, VCpu};
+
+/// PSCI 1.1
+const VERSION: u64 = 1 << 16 | 1;
+/// `MIGRATE_INFO_TYPE`: no trusted OS needing migration.
+const MIGRATE_NOT_REQUIRED: u64 = 2;
+
+const AFFINITY_ON: u64 = 0;
+const AFFINITY_OFF: u64 = 1;
+const AFFINITY_ON_PENDING: u64 = 2;
+
+/// The functions implemented, for `PSCI_FEATURES`.
+const FEATURES: [u32; 13] = [
+    PSCI_VERSION,
+    PSCI_CPU_SUSPEND_32,
+    PSCI_CPU_SUSPEND_64,
+    PSCI_CPU_OFF,
+    PSCI_CPU_ON_32,
+    PSCI_CPU_ON_64,
+    PSCI_AFFINITY_INFO_32,
+    PSCI_AFFINITY_INFO_64,
+    PSCI_MIGRATE_INFO_TYPE,
+    PSCI_SYSTEM_OFF,
+    PSCI_SYSTEM_RESET,
+    PSCI_FEATURES,
+    // Tells guests they may call `SMCCC_VERSION`.
+    SMCCC_VERSION,
+];
+
+/// Whether `function` is in the PSCI range of the standard secure service calls.
+pub fn is_psci(function: u32) -> bool {
+    matches!(function & !0x4000_0000, 0x8400_0000..=0x8400_001f)
+}
+
+fn error(code: i32) -> u64 {
+    code as i64 as u64
+}
+
+/// Handle the PSCI call in `x0` of `vcpu`, returns what goes back in `x0`. `CPU_OFF`,
+/// `SYSTEM_OFF` and `SYSTEM_RESET` do not return.
+pub fn call(vcpu: &mut VCpu) -> u64 {
+    let function = vcpu.regs.usr[0] as u32;
+    let mut args = [vcpu.regs.usr[1], vcpu.regs.usr[2], vcpu.regs.usr[3]];
+    // SMC32 calls only use the low half of the registers.
+    if function & 0x4000_0000 == 0 {
+        args.iter_mut().for_each(|arg| *arg &= 0xffff_ffff);
+    }
+    debug!("vcpu {} psci {:#x} {:x?}", vcpu.id, function, args);
+
+    match function {
+        PSCI_VERSION => VERSION,
+        PSCI_FEATURES => match FEATURES.contains(&(args[0] as u32)) {
+            true => 0,
+            false => error(NOT_SUPPORTED),
+        },
+        PSCI_MIGRATE_INFO_TYPE => MIGRATE_NOT_REQUIRED,
+        // Any power state is taken as standby: the vcpu waits for an interrupt and returns.
+        PSCI_CPU_SUSPEND_32 | PSCI_CPU_SUSPEND_64 => {
+            vcpu.wait();
+            0
+        }
+        PSCI_CPU_OFF => {
+            info!("vcpu {} of room {} off", vcpu.id, vcpu.room);
+            room::cpu_off()
+        }
+        PSCI_CPU_ON_32 | PSCI_CPU_ON_64 => cpu_on(vcpu, args[0], args[1], args[2]),
+        PSCI_AFFINITY_INFO_32 | PSCI_AFFINITY_INFO_64 => affinity_info(vcpu, args[0], args[1]),
+        PSCI_SYSTEM_OFF => {
+            info!("room {} system off", vcpu.room);
+            let _ = room::stop(vcpu.room);
+            room::park()
+        }
+        PSCI_SYSTEM_RESET => {
+            info!("room {} system reset", vcpu.room);
+            room::reset(vcpu.room)
+        }
+        _ => error(NOT_SUPPORTED),
+    }
+}
+
+/// The cpu of `vcpu`'s room running the vcpu with affinity `mpidr`.
+fn target_cpu(vcpu: &VCpu, mpidr: u64) -> Option<CPUId> {
+    let n = vcpu_of(mpidr)?;
+    room::with(vcpu.room, |room| room.cpus.get(n).copied())
+        .ok()
+        .flatten()
+}
+
+fn cpu_on(vcpu: &VCpu, mpidr: u64, entry: u64, context: u64) -> u64 {
+    let Some(cpu) = target_cpu(vcpu, mpidr) else {
+        return error(INVALID_PARAMETERS);
+    };
+    let target = get_cpu_data(cpu);
+    if target.has_vcpu() {
+        return error(ALREADY_ON);
+    }
+    if target.peek_request().is_some() {
+        return error(ON_PENDING);
+    }
+    target.post(CpuRequest::CpuOn {
+        room: vcpu.room,
+        entry: entry as _,
+        context: context as _,
+    });
+    0
+}
+
+fn affinity_info(vcpu: &VCpu, mpidr: u64, level: u64) -> u64 {
+    if level != 0 {
+        return error(INVALID_PARAMETERS);
+    }
+    let Some(cpu) = target_cpu(vcpu, mpidr) else {
+        return error(INVALID_PARAMETERS);
+    };
+    let target = get_cpu_data(cpu);
+    if cpu == cpu_data().id || target.has_vcpu() {
+        AFFINITY_ON
+    } else if let Some(CpuRequest::CpuOn { .. } | CpuRequest::Run(_)) = target.peek_request() {
+        AFFINITY_ON_PENDING
+    } else {
+        AFFINITY_OFF
+    }
+}
@@ -0,0 +1,106 @@
+//! SMCCC calls of guests, by the owner in their function id. The Arm architecture calls are
+//! answered here, PSCI goes to [`vpsci`], and the calls of the owners the room is allowed are
+//! forwarded to the firmware. Everything else is `NOT_SUPPORTED`.
+
+use log::debug;
+use qhyper_config::SmcOwners;
+use smccc::{
+    arch::{
+        error::NOT_SUPPORTED, SMCCC_ARCH_FEATURES, SMCCC_ARCH_WORKAROUND_1,
+        SMCCC_ARCH_WORKAROUND_2, SMCCC_ARCH_WORKAROUND_3, SMCCC_VERSION,
+    },
+    smc64,
+};
+
+use crate::room;
+
+use super::{vpsci, VCpu};
+
+/// SMCCC 1.1
+const VERSION: u64 = 1 << 16 | 1;
+
+const OWNER_ARCH: u32 = 0;
+
+/// Whether `function` is an SMCCC call, the others are qhyper hypercalls: their ids have
+/// neither the fast call bit nor an owner.
+pub fn is_smccc(function: u64) -> bool {
+    function as u32 >> 24 != 0
+}
+
+fn owner(function: u32) -> u32 {
+    function >> 24 & 0x3f
+}
+
+fn error(code: i32) -> u64 {
+    code as i64 as u64
+}
+
+/// Handle the SMCCC call in `x0` of `vcpu`, the results go back in `x0` to `x3`.
+pub fn call(vcpu: &mut VCpu) {
+    let function = vcpu.regs.usr[0] as u32;
+    let owner = owner(function);
+
+    if vpsci::is_psci(function) {
+        vcpu.regs.usr[0] = vpsci::call(vcpu);
+        return;
+    }
+    match (owner, function) {
+        (OWNER_ARCH, SMCCC_VERSION) => vcpu.regs.usr[0] = VERSION,
+        (OWNER_ARCH, SMCCC_ARCH_FEATURES) => {
+            vcpu.regs.usr[0] = arch_features(vcpu.regs.usr[1] as u32)
+        }
+        // Mitigations run in the firmware.
+        (
+            OWNER_ARCH,
+            SMCCC_ARCH_WORKAROUND_1 | SMCCC_ARCH_WORKAROUND_2 | SMCCC_ARCH_WORKAROUND_3,
+        ) => forward(vcpu),
+        (OWNER_ARCH, _) => vcpu.regs.usr[0] = error(NOT_SUPPORTED),
+        _ if allowed(vcpu, owner) => forward(vcpu),
+        _ => {
+            debug!(
+                "room {} smc {:#x} of owner {} denied",
+                vcpu.room, function, owner
+            );
+            vcpu.regs.usr[0] = error(NOT_SUPPORTED);
+        }
+    }
+}
+
+/// `SMCCC_ARCH_FEATURES`: the workarounds are what the firmware has.
+fn arch_features(function: u32) -> u64 {
+    match function {
+        SMCCC_VERSION | SMCCC_ARCH_FEATURES => 0,
+        SMCCC_ARCH_WORKAROUND_1 | SMCCC_ARCH_WORKAROUND_2 | SMCCC_ARCH_WORKAROUND_3 => {
+            if firmware(SMCCC_VERSION, &[])[0] as i64 >= VERSION as i64 {
+                firmware(SMCCC_ARCH_FEATURES, &[function as u64])[0]
+            } else {
+                error(NOT_SUPPORTED)
+            }
+        }
+        _ => error(NOT_SUPPORTED),
+    }
+}
+
+/// The root room may use the firmware as it likes, other rooms the owners of their config.
+/// Neither has the hypervisor service owners, which would be ours.
+fn allowed(vcpu: &VCpu, owner: u32) -> bool {
+    let owners = room::with(vcpu.room, |room| {
+        room.config
+            .as_ref()
+            .map_or(SmcOwners::all(), |config| config.smc_owners)
+    })
+    .unwrap_or_default();
+    owners.bits() & 1 << owner != 0
+}
+
+/// Pass the call on to the firmware with `x1` to `x17`, `x0` to `x3` come back.
+fn forward(vcpu: &mut VCpu) {
+    let ret = firmware(vcpu.regs.usr[0] as u32, &vcpu.regs.usr[1..18]);
+    vcpu.regs.usr[..4].copy_from_slice(&ret[..4]);
+}
+
+fn firmware(function: u32, args: &[u64]) -> [u64; 18] {
+    let mut regs = [0; 17];
+    regs[..args.len()].copy_from_slice(args);
+    smc64(function, regs)
+}
@@ -0,0 +1,99 @@
+//! Emulated MMIO: guest accesses to IPA ranges without stage-2 mapping go to handlers.
+
+use core::ops::Range;
+
+use alloc::{format, sync::Arc, vec::Vec};
+use log::trace;
+
+use crate::{
+    error::HvResult,
+    hv_err, hv_result_err,
+    room::{self, RoomId},
+};
+
+/// An emulated device, `offset` is relative to the start of the range it is registered at.
+pub trait MmioHandler: Send + Sync {
+    fn read(&self, offset: usize, width: usize) -> HvResult<u64>;
+    fn write(&self, offset: usize, width: usize, value: u64) -> HvResult;
+}
+
+/// A decoded guest access, `value` is the data written or receives the data read.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    pub address: usize,
+    /// In bytes: 1, 2, 4 or 8.
+    pub width: usize,
+    pub is_write: bool,
+    pub value: u64,
+}
+
+struct MmioRegion {
+    range: Range<usize>,
+    handler: Arc<dyn MmioHandler>,
+}
+
+/// The emulated devices of a room.
+#[derive(Default)]
+pub struct MmioBus {
+    regions: Vec<MmioRegion>,
+}
+
+impl MmioBus {
+    pub fn register(&mut self, range: Range<usize>, handler: Arc<dyn MmioHandler>) -> HvResult {
+        if let Some(r) = self
+            .regions
+            .iter()
+            .find(|r| r.range.start < range.end && range.start < r.range.end)
+        {
+            return hv_result_err!(
+                EEXIST,
+                format!("mmio {:#x?} overlaps {:#x?}", range, r.range)
+            );
+        }
+        self.regions.push(MmioRegion { range, handler });
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, start: usize) {
+        self.regions.retain(|r| r.range.start != start);
+    }
+
+    /// The handler covering `address` and its range.
+    pub fn find(&self, address: usize) -> Option<(Range<usize>, Arc<dyn MmioHandler>)> {
+        self.regions
+            .iter()
+            .find(|r| r.range.contains(&address))
+            .map(|r| (r.range.clone(), r.handler.clone()))
+    }
+}
+
+/// Emulate `access` of room `id`, the handler runs without the room locked. Accesses
+/// running past the end of the range are refused, its handler does not know what follows.
+pub fn handle(id: RoomId, access: &mut MmioAccess) -> HvResult {
+    let (range, handler) =
+        room::with(id, |room| room.mmio.find(access.address))?.ok_or_else(|| {
+            hv_err!(
+                EFAULT,
+                format!("room {} no mmio handler at {:#x}", id, access.address)
+            )
+        })?;
+
+    if access.address + access.width > range.end {
+        return hv_result_err!(
+            EFAULT,
+            format!(
+                "room {} mmio {:#x} width {} past {:#x}",
+                id, access.address, access.width, range.end
+            )
+        );
+    }
+
+    let offset = access.address - range.start;
+    if access.is_write {
+        handler.write(offset, access.width, access.value)?;
+    } else {
+        access.value = handler.read(offset, access.width)?;
+    }
+    trace!("mmio {:x?}", access);
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! virtio-blk emulated in the hypervisor on a disk image in RAM no guest maps, a
+//! [`MemFlags::DISK`](qhyper_config::MemFlags::DISK) region of the cell.
+
+use core::{
+    ops::Range,
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+
+use alloc::format;
+
+use crate::{
+    error::HvResult,
+    hv_err, hv_result_err,
+    mem::{self, PAGE_SIZE_4K},
+    room::RoomId,
+};
+
+use super::{
+    queue::{Chain, Virtqueue},
+    transport::{read_config_bytes, VirtioDevice},
+};
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const SECTOR_SIZE: usize = 512;
+/// The request header: type, reserved and sector.
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+pub struct Block {
+    /// The image, identity mapped in the hypervisor.
+    disk: Range<usize>,
+    read_only: bool,
+    id: [u8; ID_SIZE],
+}
+
+impl Block {
+    /// A disk on the host memory `disk`, named `serial` for `GET_ID`.
+    pub fn new(disk: Range<usize>, read_only: bool, serial: &str) -> HvResult<Self> {
+        let in_ram = mem::guest_ram()
+            .any(|r| r.start.as_usize() <= disk.start && disk.end <= r.end.as_usize());
+        if !in_ram || disk.start % PAGE_SIZE_4K != 0 || disk.len() % SECTOR_SIZE != 0 {
+            return hv_result_err!(EINVAL, format!("bad disk {:#x?}", disk));
+        }
+        let mut id = [0u8; ID_SIZE];
+        let len = serial.len().min(ID_SIZE);
+        id[..len].copy_from_slice(&serial.as_bytes()[..len]);
+        Ok(Self {
+            disk,
+            read_only,
+            id,
+        })
+    }
+
+    fn sectors(&self) -> u64 {
+        (self.disk.len() / SECTOR_SIZE) as u64
+    }
+
+    /// The bytes of the image from `sector`, `None` past its end.
+    fn range(&self, sector: u64, len: usize) -> Option<Range<usize>> {
+        let start = (sector as usize).checked_mul(SECTOR_SIZE)?;
+        let end = start.checked_add(len)?;
+        (end <= self.disk.len()).then_some(self.disk.start + start..self.disk.start + end)
+    }
+
+    /// Serve one request, returns the status and the bytes written to the guest before it.
+    fn serve(&self, id: RoomId, chain: &Chain) -> HvResult<(u8, usize)> {
+        let mut header = [0u8; HEADER_SIZE];
+        if chain.read(id, 0, &mut header)? < HEADER_SIZE || chain.writable_len() == 0 {
+            return hv_result_err!(EINVAL, format!("room {} bad block request", id));
+        }
+        let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        // The status takes the last byte the device writes.
+        let data_in = chain.writable_len() - 1;
+
+        Ok(match kind {
+            T_IN => match self.range(sector, data_in) {
+                Some(range) => {
+                    let data = unsafe { from_raw_parts(range.start as *const u8, range.len()) };
+                    (S_OK, chain.write(id, 0, data)?)
+                }
+                None => (S_IOERR, 0),
+            },
+            T_OUT if self.read_only => (S_IOERR, 0),
+            T_OUT => match self.range(sector, chain.readable_len() - HEADER_SIZE) {
+                Some(range) => {
+                    let data = unsafe { from_raw_parts_mut(range.start as *mut u8, range.len()) };
+                    chain.read(id, HEADER_SIZE, data)?;
+                    (S_OK, 0)
+                }
+                None => (S_IOERR, 0),
+            },
+            // Writes land in RAM right away.
+            T_FLUSH => (S_OK, 0),
+            T_GET_ID => (S_OK, chain.write(id, 0, &self.id[..data_in.min(ID_SIZE)])?),
+            _ => (S_UNSUPP, 0),
+        })
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        match self.read_only {
+            true => F_FLUSH | F_RO,
+            false => F_FLUSH,
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    /// Only `capacity`, in sectors, at the start.
+    fn read_config(&self, offset: usize, width: usize) -> u64 {
+        read_config_bytes(&self.sectors().to_le_bytes(), offset, width)
+    }
+
+    fn notify(&self, id: RoomId, _index: usize, queue: &mut Virtqueue) -> HvResult<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(id)? {
+            let (status, len) = self.serve(id, &chain)?;
+            chain.write(id, chain.writable_len() - 1, &[status])?;
+            queue.push_used(id, chain.head, len as u32 + 1)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
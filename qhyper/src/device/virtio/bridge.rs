@@ -0,0 +1,242 @@
+//! Virtio bridge: the virtio-mmio windows of non-root rooms are served by a backend in the
+//! root room. Their accesses are queued as requests in a page shared with the backend, which
+//! is notified with [`VIRTIO_BRIDGE_IRQ`] and answers in the response ring; the vcpu waits
+//! for its answer. Used buffers are signalled with the `VirtioInjectIrq` hypercall.
+//!
+//! The shared page holds the two rings, each with a front (consumer) and a rear (producer)
+//! index: requests are produced here and consumed by the backend, responses the other way.
+//! A response carries the `seq` of its request, those of accesses given up are dropped.
+
+use core::{
+    hint::spin_loop,
+    mem::size_of,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+
+use alloc::{collections::btree_map::BTreeMap, format};
+use log::{debug, info};
+use spin::Mutex;
+
+use crate::{
+    device::mmio::MmioHandler,
+    error::HvResult,
+    hv_err, hv_result_err,
+    mem::PAGE_SIZE_4K,
+    percpu::cpu_data,
+    room::{self, RoomId, ROOT_ROOM},
+};
+
+/// SPI injected into the root room when requests are queued, no device raises it.
+pub const VIRTIO_BRIDGE_IRQ: u32 = 32 + 0x20;
+/// Entries of each ring.
+pub const RING_SIZE: usize = 64;
+
+pub static VIRTIO_BRIDGE: Mutex<VirtioBridgeRegion> = Mutex::new(VirtioBridgeRegion::default());
+static NEXT_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// A guest access, as the backend reads it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtioRequest {
+    pub room: u32,
+    /// The cpu waiting for the response.
+    pub cpu: u32,
+    /// IPA in the room.
+    pub address: u64,
+    /// In bytes.
+    pub width: u32,
+    pub is_write: u32,
+    pub value: u64,
+    /// Returned in the response.
+    pub seq: u32,
+    _reserved: u32,
+}
+
+/// The answer to the request of `cpu`, `value` is the data read.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtioResponse {
+    pub cpu: u32,
+    pub seq: u32,
+    pub value: u64,
+}
+
+/// The layout of the shared page.
+#[repr(C)]
+pub struct VirtioRings {
+    pub req_front: u32,
+    pub req_rear: u32,
+    pub res_front: u32,
+    pub res_rear: u32,
+    pub requests: [VirtioRequest; RING_SIZE],
+    pub responses: [VirtioResponse; RING_SIZE],
+}
+
+const _: () = assert!(size_of::<VirtioRings>() <= PAGE_SIZE_4K);
+
+pub struct VirtioBridgeRegion {
+    base_address: usize, // el1 and el2 shared region addr, el2 virtual address
+    pub is_enable: bool,
+    /// The request each cpu waits for the response of, by `seq`.
+    waiting: BTreeMap<u32, u32>,
+    /// Responses taken from the ring for cpus that have not looked yet.
+    replies: BTreeMap<u32, u64>,
+}
+
+impl VirtioBridgeRegion {
+    pub const fn default() -> Self {
+        VirtioBridgeRegion {
+            base_address: 0,
+            is_enable: false,
+            waiting: BTreeMap::new(),
+            replies: BTreeMap::new(),
+        }
+    }
+
+    /// Use the page at `base_addr` for the rings, both start empty.
+    pub fn init_addr(&mut self, base_addr: usize) {
+        self.base_address = base_addr;
+        self.is_enable = true;
+        self.waiting.clear();
+        self.replies.clear();
+        let rings = self.rings();
+        unsafe {
+            (&raw mut (*rings).req_front).write_volatile(0);
+            (&raw mut (*rings).req_rear).write_volatile(0);
+            (&raw mut (*rings).res_front).write_volatile(0);
+            (&raw mut (*rings).res_rear).write_volatile(0);
+        }
+        info!("virtio bridge at {:#x}", base_addr);
+    }
+
+    fn rings(&self) -> *mut VirtioRings {
+        self.base_address as *mut VirtioRings
+    }
+
+    /// Queue `request`, `false` if the ring is full.
+    fn push(&mut self, request: VirtioRequest) -> bool {
+        let rings = self.rings();
+        unsafe {
+            let front = (&raw const (*rings).req_front).read_volatile() as usize;
+            let rear = (&raw const (*rings).req_rear).read_volatile() as usize;
+            if (rear + 1) % RING_SIZE == front % RING_SIZE {
+                return false;
+            }
+            (&raw mut (*rings).requests[rear % RING_SIZE]).write_volatile(request);
+            fence(Ordering::Release);
+            (&raw mut (*rings).req_rear).write_volatile(((rear + 1) % RING_SIZE) as u32);
+        }
+        self.waiting.insert(request.cpu, request.seq);
+        true
+    }
+
+    /// `cpu` no longer waits, its response is dropped when it comes.
+    fn abandon(&mut self, cpu: u32) {
+        self.waiting.remove(&cpu);
+        self.replies.remove(&cpu);
+    }
+
+    /// Move the responses out of the ring, then take the one of `cpu` if it came. The
+    /// backend writes the indices, they are kept in the ring.
+    fn take_reply(&mut self, cpu: u32) -> Option<u64> {
+        let rings = self.rings();
+        unsafe {
+            let mut front = (&raw const (*rings).res_front).read_volatile() as usize % RING_SIZE;
+            let rear = (&raw const (*rings).res_rear).read_volatile() as usize % RING_SIZE;
+            fence(Ordering::Acquire);
+            while front != rear {
+                let res = (&raw const (*rings).responses[front]).read_volatile();
+                match self.waiting.get(&res.cpu) {
+                    Some(&seq) if seq == res.seq => {
+                        self.waiting.remove(&res.cpu);
+                        self.replies.insert(res.cpu, res.value);
+                    }
+                    _ => debug!("virtio stale response {} for cpu {}", res.seq, res.cpu),
+                }
+                front = (front + 1) % RING_SIZE;
+            }
+            (&raw mut (*rings).res_front).write_volatile(front as u32);
+        }
+        self.replies.remove(&cpu)
+    }
+}
+
+/// Hand an access of room `id` to the backend and wait for its answer. The access is dropped
+/// if the cpu is asked to leave the room in the meantime.
+fn request(id: RoomId, address: usize, width: usize, is_write: bool, value: u64) -> HvResult<u64> {
+    let cpu = cpu_data().id.raw() as u32;
+    let request = VirtioRequest {
+        room: id as _,
+        cpu,
+        address: address as _,
+        width: width as _,
+        is_write: is_write as _,
+        value,
+        seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+        _reserved: 0,
+    };
+    loop {
+        {
+            let mut bridge = VIRTIO_BRIDGE.lock();
+            if !bridge.is_enable {
+                return hv_result_err!(ENODEV, "no virtio backend");
+            }
+            if bridge.push(request) {
+                break;
+            }
+        }
+        if cpu_data().peek_request().is_some() {
+            return Ok(0);
+        }
+        spin_loop();
+    }
+    room::inject_irq(ROOT_ROOM, VIRTIO_BRIDGE_IRQ)?;
+
+    loop {
+        if let Some(value) = VIRTIO_BRIDGE.lock().take_reply(cpu) {
+            return Ok(value);
+        }
+        if cpu_data().peek_request().is_some() {
+            VIRTIO_BRIDGE.lock().abandon(cpu);
+            debug!("virtio request of room {} at {:#x} dropped", id, address);
+            return Ok(0);
+        }
+        spin_loop();
+    }
+}
+
+/// A virtio-mmio window of a non-root room, served by the backend.
+pub struct VirtioMmio {
+    pub room: RoomId,
+    /// IPA of the window.
+    pub base: usize,
+}
+
+impl MmioHandler for VirtioMmio {
+    fn read(&self, offset: usize, width: usize) -> HvResult<u64> {
+        request(self.room, self.base + offset, width, false, 0)
+    }
+
+    fn write(&self, offset: usize, width: usize, value: u64) -> HvResult {
+        request(self.room, self.base + offset, width, true, value)?;
+        Ok(())
+    }
+}
+
+/// Check that the rings fit in memory of the root room at `ipa`, which it sees at the same
+/// address as the hypervisor.
+pub fn check_region(ipa: usize) -> HvResult {
+    if ipa % PAGE_SIZE_4K != 0 {
+        return hv_result_err!(EINVAL, format!("virtio region {:#x} not aligned", ipa));
+    }
+    let mut probe = [0u8; 1];
+    room::with(ROOT_ROOM, |root| {
+        root.space.copy_from_guest(ipa, &mut probe)
+    })?
+    .map_err(|_| {
+        hv_err!(
+            EFAULT,
+            format!("virtio region {:#x} not in root memory", ipa)
+        )
+    })
+}
@@ -0,0 +1,92 @@
+//! virtio-console emulated in the hypervisor: what a room writes to port 0 goes to the UART
+//! of the hypervisor, every line starting with the name of the room. Nothing is received.
+
+use alloc::{format, string::String};
+use spin::Mutex;
+
+use crate::{debug, error::HvResult, room::RoomId};
+
+use super::{queue::Virtqueue, transport::VirtioDevice};
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+const F_EMERG_WRITE: u64 = 1 << 2;
+
+const TRANSMITQ: usize = 1;
+/// `emerg_wr` in the config space.
+const CONFIG_EMERG_WR: usize = 8;
+
+/// Bytes copied out of guest memory at once.
+const CHUNK: usize = 256;
+
+/// Keeps the output of one room together on the UART.
+static OUTPUT: Mutex<()> = Mutex::new(());
+
+pub struct Console {
+    prefix: String,
+    /// Whether the next byte starts a line and needs the prefix.
+    line_start: Mutex<bool>,
+}
+
+impl Console {
+    pub fn new(name: &str) -> Self {
+        Self {
+            prefix: format!("[{}] ", name),
+            line_start: Mutex::new(true),
+        }
+    }
+
+    fn output(&self, bytes: &[u8]) {
+        let _output = OUTPUT.lock();
+        let mut line_start = self.line_start.lock();
+        for &byte in bytes {
+            if *line_start {
+                self.prefix.bytes().for_each(debug::put);
+            }
+            debug::put(byte);
+            *line_start = byte == b'\n';
+        }
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        F_EMERG_WRITE
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn write_config(&self, offset: usize, _width: usize, value: u64) {
+        if offset == CONFIG_EMERG_WR {
+            self.output(&[value as u8]);
+        }
+    }
+
+    fn notify(&self, id: RoomId, index: usize, queue: &mut Virtqueue) -> HvResult<bool> {
+        // The receive buffers stay with the device, there is no input.
+        if index != TRANSMITQ {
+            return Ok(false);
+        }
+        let mut used = false;
+        let mut buf = [0u8; CHUNK];
+        while let Some(chain) = queue.pop(id)? {
+            let mut offset = 0;
+            loop {
+                let len = chain.read(id, offset, &mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                self.output(&buf[..len]);
+                offset += len;
+            }
+            queue.push_used(id, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
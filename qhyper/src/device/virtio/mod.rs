@@ -0,0 +1,10 @@
+//! Virtio-mmio devices of the rooms: either bridged to a backend in the root room, or
+//! emulated here on top of the [`transport`] and the virtqueues in guest memory.
+
+pub mod blk;
+pub mod bridge;
+pub mod console;
+pub mod net;
+pub mod queue;
+pub mod switch;
+pub mod transport;
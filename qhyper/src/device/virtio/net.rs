@@ -0,0 +1,173 @@
+//! virtio-net emulated in the hypervisor: a network card on a port of the [`switch`], which
+//! carries the frames between the cells. Each port may be limited to a rate, what the guest
+//! sends beyond it is dropped.
+//!
+//! [`switch`]: super::switch
+
+use core::mem::take;
+
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+use crate::{error::HvResult, room::RoomId, time::Instant};
+
+use super::{
+    queue::Virtqueue,
+    switch::{self, Mac},
+    transport::{read_config_bytes, MmioTransport, VirtioDevice},
+};
+
+const VIRTIO_ID_NET: u32 = 1;
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+const S_LINK_UP: u16 = 1;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// `virtio_net_hdr` up to `num_buffers`. No offload is offered so only `num_buffers` is set.
+const HEADER_SIZE: usize = 12;
+const HEADER_NUM_BUFFERS: usize = 10;
+/// Destination and source addresses, and the ethertype.
+const ETH_HEADER_SIZE: usize = 14;
+/// Largest frame without FCS, with a VLAN tag.
+const FRAME_MAX: usize = 1518;
+
+/// Bytes a limited port may send at once after being idle, at least.
+const BURST_MIN: u64 = 16 * 1024;
+
+/// Token bucket of a limited port, in bytes.
+struct Bucket {
+    bytes_per_sec: u64,
+    burst: u64,
+    tokens: u64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(mbit_per_sec: u32) -> Self {
+        let bytes_per_sec = mbit_per_sec as u64 * 1_000_000 / 8;
+        let burst = (bytes_per_sec / 100).max(BURST_MIN);
+        Self {
+            bytes_per_sec,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take `len` bytes if the rate allows them.
+    fn admit(&mut self, len: usize) -> bool {
+        let now = Instant::now();
+        let refill =
+            now.duration_since(self.last).as_nanos() * self.bytes_per_sec as u128 / 1_000_000_000;
+        // Time too short to earn a byte is kept for the next frame.
+        if refill > 0 {
+            self.tokens = (self.tokens + refill.min(self.burst as u128) as u64).min(self.burst);
+            self.last = now;
+        }
+        match self.tokens.checked_sub(len as u64) {
+            Some(tokens) => {
+                self.tokens = tokens;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct Net {
+    mac: Mac,
+    /// `None` if the port is not limited.
+    bucket: Option<Mutex<Bucket>>,
+    /// Frames sent by the guest, forwarded once its queues are unlocked.
+    outbox: Mutex<Vec<Vec<u8>>>,
+}
+
+impl Net {
+    /// A card with address `mac`, limited to `rate` Mbit/s unless it is 0.
+    pub fn new(mac: Mac, rate: u32) -> Self {
+        Self {
+            mac,
+            bucket: (rate != 0).then(|| Mutex::new(Bucket::new(rate))),
+            outbox: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn mac(&self) -> Mac {
+        self.mac
+    }
+
+    fn admit(&self, len: usize) -> bool {
+        self.bucket
+            .as_ref()
+            .is_none_or(|bucket| bucket.lock().admit(len))
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        F_MAC | F_STATUS
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    /// `mac` then `status`.
+    fn read_config(&self, offset: usize, width: usize) -> u64 {
+        let mut config = [0u8; 8];
+        config[..6].copy_from_slice(&self.mac);
+        config[6..].copy_from_slice(&S_LINK_UP.to_le_bytes());
+        read_config_bytes(&config, offset, width)
+    }
+
+    fn notify(&self, id: RoomId, index: usize, queue: &mut Virtqueue) -> HvResult<bool> {
+        // Receive buffers are filled as frames come.
+        if index != TRANSMITQ {
+            return Ok(false);
+        }
+        let mut used = false;
+        let mut outbox = self.outbox.lock();
+        while let Some(chain) = queue.pop(id)? {
+            let len = chain.readable_len().saturating_sub(HEADER_SIZE);
+            if (ETH_HEADER_SIZE..=FRAME_MAX).contains(&len) && self.admit(len) {
+                let mut frame = vec![0u8; len];
+                chain.read(id, HEADER_SIZE, &mut frame)?;
+                outbox.push(frame);
+            }
+            queue.push_used(id, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn after_notify(&self, _id: RoomId) {
+        let frames = take(&mut *self.outbox.lock());
+        for frame in frames {
+            switch::forward(self.mac, &frame);
+        }
+    }
+
+    fn reset(&self) {
+        self.outbox.lock().clear();
+    }
+}
+
+/// Hand `frame` to the guest behind `port`, it is dropped if no receive buffer is free.
+pub fn deliver(port: &MmioTransport<Net>, frame: &[u8]) -> HvResult {
+    port.with_queue(RECEIVEQ, |id, queue| {
+        let Some(chain) = queue.pop(id)? else {
+            return Ok(false);
+        };
+        let mut header = [0u8; HEADER_SIZE];
+        header[HEADER_NUM_BUFFERS..].copy_from_slice(&1u16.to_le_bytes());
+        let len = chain.write(id, 0, &header)? + chain.write(id, HEADER_SIZE, frame)?;
+        queue.push_used(id, chain.head, len as u32)?;
+        Ok(true)
+    })
+}
@@ -0,0 +1,177 @@
+//! Split virtqueues in the memory of a room: the descriptor table, the avail ring the driver
+//! produces and the used ring the device answers in.
+
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::{format, vec::Vec};
+
+use crate::{
+    error::HvResult,
+    hv_err, hv_result_err,
+    room::{self, RoomId},
+};
+
+const DESC_SIZE: usize = 16;
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+/// Largest queue offered to drivers.
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+pub fn read_guest(id: RoomId, ipa: usize, buf: &mut [u8]) -> HvResult {
+    room::with(id, |room| room.space.copy_from_guest(ipa, buf))?
+}
+
+pub fn write_guest(id: RoomId, ipa: usize, data: &[u8]) -> HvResult {
+    room::with(id, |room| room.space.copy_to_guest(ipa, data))?
+}
+
+fn read_u16(id: RoomId, ipa: usize) -> HvResult<u16> {
+    let mut raw = [0u8; 2];
+    read_guest(id, ipa, &mut raw)?;
+    Ok(u16::from_le_bytes(raw))
+}
+
+/// A guest buffer, `writable` if the device fills it.
+#[derive(Debug, Clone, Copy)]
+pub struct Desc {
+    pub addr: usize,
+    pub len: usize,
+    pub writable: bool,
+}
+
+/// The descriptors of one request: the part the driver wrote, then the part for the device.
+#[derive(Debug)]
+pub struct Chain {
+    pub head: u16,
+    pub descs: Vec<Desc>,
+}
+
+impl Chain {
+    fn part(&self, writable: bool) -> impl Iterator<Item = &Desc> {
+        self.descs.iter().filter(move |d| d.writable == writable)
+    }
+
+    pub fn readable_len(&self) -> usize {
+        self.part(false).map(|d| d.len).sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.part(true).map(|d| d.len).sum()
+    }
+
+    /// Copy the driver part from `offset` into `buf`, returns the bytes copied.
+    pub fn read(&self, id: RoomId, offset: usize, buf: &mut [u8]) -> HvResult<usize> {
+        let mut done = 0;
+        let mut skip = offset;
+        for desc in self.part(false) {
+            if done == buf.len() {
+                break;
+            }
+            if skip >= desc.len {
+                skip -= desc.len;
+                continue;
+            }
+            let len = (desc.len - skip).min(buf.len() - done);
+            read_guest(id, desc.addr + skip, &mut buf[done..done + len])?;
+            done += len;
+            skip = 0;
+        }
+        Ok(done)
+    }
+
+    /// Copy `data` to the device part from `offset`, returns the bytes copied.
+    pub fn write(&self, id: RoomId, offset: usize, data: &[u8]) -> HvResult<usize> {
+        let mut done = 0;
+        let mut skip = offset;
+        for desc in self.part(true) {
+            if done == data.len() {
+                break;
+            }
+            if skip >= desc.len {
+                skip -= desc.len;
+                continue;
+            }
+            let len = (desc.len - skip).min(data.len() - done);
+            write_guest(id, desc.addr + skip, &data[done..done + len])?;
+            done += len;
+            skip = 0;
+        }
+        Ok(done)
+    }
+}
+
+/// A queue as the driver set it up, addresses are IPAs.
+#[derive(Debug, Default, Clone)]
+pub struct Virtqueue {
+    pub num: u16,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    last_avail: u16,
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    /// Take the next request the driver made available.
+    pub fn pop(&mut self, id: RoomId) -> HvResult<Option<Chain>> {
+        if !self.ready || self.num == 0 {
+            return Ok(None);
+        }
+        let avail_idx = read_u16(id, self.driver as usize + 2)?;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail % self.num) as usize;
+        let head = read_u16(id, self.driver as usize + 4 + slot * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut descs = Vec::new();
+        let mut index = head;
+        loop {
+            if index >= self.num || descs.len() >= self.num as usize {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("room {} bad descriptor chain from {}", id, head)
+                );
+            }
+            let mut raw = [0u8; DESC_SIZE];
+            read_guest(
+                id,
+                self.desc as usize + index as usize * DESC_SIZE,
+                &mut raw,
+            )?;
+            let flags = u16::from_le_bytes([raw[12], raw[13]]);
+            descs.push(Desc {
+                addr: u64::from_le_bytes(raw[0..8].try_into().unwrap()) as usize,
+                len: u32::from_le_bytes(raw[8..12].try_into().unwrap()) as usize,
+                writable: flags & DESC_F_WRITE != 0,
+            });
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = u16::from_le_bytes([raw[14], raw[15]]);
+        }
+        Ok(Some(Chain { head, descs }))
+    }
+
+    /// Give request `head` back, with `len` bytes written to its device part.
+    pub fn push_used(&mut self, id: RoomId, head: u16, len: u32) -> HvResult {
+        let slot = (self.used_idx % self.num) as usize;
+        let mut elem = [0u8; 8];
+        elem[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..8].copy_from_slice(&len.to_le_bytes());
+        write_guest(id, self.device as usize + 4 + slot * 8, &elem)?;
+        fence(Ordering::Release);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        write_guest(id, self.device as usize + 2, &self.used_idx.to_le_bytes())
+    }
+
+    /// Whether the driver wants an interrupt for used requests.
+    pub fn wants_interrupt(&self, id: RoomId) -> HvResult<bool> {
+        Ok(read_u16(id, self.driver as usize)? & AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
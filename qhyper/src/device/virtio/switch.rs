@@ -0,0 +1,129 @@
+//! The L2 switch between the network cards of the cells. It learns behind which port each
+//! source address is, and floods the frames to unknown, broadcast and multicast addresses
+//! to every other port. A port only sends from its own address, so a cell can not take the
+//! traffic of another.
+
+use core::time::Duration;
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    format,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use log::{debug, info};
+use spin::Mutex;
+
+use crate::{error::HvResult, hv_err, hv_result_err, time::Instant};
+
+use super::{
+    net::{self, Net},
+    transport::MmioTransport,
+};
+
+pub type Mac = [u8; 6];
+
+/// How long a learnt address is trusted.
+const AGEING: Duration = Duration::from_secs(300);
+/// Addresses learnt at most.
+const TABLE_MAX: usize = 1024;
+
+static SWITCH: Mutex<Switch> = Mutex::new(Switch {
+    ports: BTreeMap::new(),
+    table: BTreeMap::new(),
+});
+
+struct Switch {
+    /// By the address of the card, gone with the room.
+    ports: BTreeMap<Mac, Weak<MmioTransport<Net>>>,
+    /// The port each source address was last seen on, and when.
+    table: BTreeMap<Mac, (Mac, Instant)>,
+}
+
+impl Switch {
+    fn learn(&mut self, src: Mac, port: Mac) {
+        if is_multicast(src) {
+            return;
+        }
+        if self.table.len() >= TABLE_MAX && !self.table.contains_key(&src) {
+            self.table.retain(|_, (_, seen)| seen.elapsed() < AGEING);
+            if self.table.len() >= TABLE_MAX {
+                return;
+            }
+        }
+        self.table.insert(src, (port, Instant::now()));
+    }
+
+    fn lookup(&self, dst: Mac) -> Option<Mac> {
+        self.table
+            .get(&dst)
+            .filter(|(_, seen)| seen.elapsed() < AGEING)
+            .map(|(port, _)| *port)
+    }
+}
+
+fn is_multicast(mac: Mac) -> bool {
+    mac[0] & 1 != 0
+}
+
+/// Plug `port` into the switch, it leaves when its room drops it.
+pub fn attach(port: &Arc<MmioTransport<Net>>) -> HvResult {
+    let mac = port.device.mac();
+    let mut switch = SWITCH.lock();
+    switch.ports.retain(|_, port| port.strong_count() > 0);
+    if switch.ports.contains_key(&mac) {
+        return hv_result_err!(EEXIST, format!("switch port {:02x?} exists", mac));
+    }
+    switch.ports.insert(mac, Arc::downgrade(port));
+    info!("switch port {:02x?} attached", mac);
+    Ok(())
+}
+
+/// Forget the ports whose room is gone, and what was learnt behind them.
+pub fn prune() {
+    let mut switch = SWITCH.lock();
+    switch.ports.retain(|_, port| port.strong_count() > 0);
+    let Switch { ports, table } = &mut *switch;
+    table.retain(|_, (port, _)| ports.contains_key(port));
+}
+
+/// Carry `frame` sent on port `from` to its destinations.
+pub fn forward(from: Mac, frame: &[u8]) {
+    let dst: Mac = frame[0..6].try_into().unwrap();
+    let src: Mac = frame[6..12].try_into().unwrap();
+    if src != from {
+        debug!("switch port {:02x?} sent from {:02x?}, dropped", from, src);
+        return;
+    }
+
+    // The ports are served without the switch locked.
+    let targets = {
+        let mut switch = SWITCH.lock();
+        switch.learn(src, from);
+        let known = match is_multicast(dst) {
+            true => None,
+            false => switch.lookup(dst),
+        };
+        match known {
+            Some(port) if port == from => vec![],
+            Some(port) => switch
+                .ports
+                .get(&port)
+                .and_then(Weak::upgrade)
+                .into_iter()
+                .collect(),
+            None => switch
+                .ports
+                .iter()
+                .filter(|(mac, _)| **mac != from)
+                .filter_map(|(_, port)| port.upgrade())
+                .collect::<Vec<_>>(),
+        }
+    };
+    for port in targets {
+        if let Err(e) = net::deliver(&port, frame) {
+            debug!("switch port {:02x?}: {:?}", port.device.mac(), e);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! The virtio-mmio register layout (version 2) of the devices emulated in the hypervisor.
+//! Requests are served on the vcpu writing `QueueNotify`, used requests raise the SPI of the
+//! device in its room.
+
+use alloc::{vec, vec::Vec};
+use log::warn;
+use spin::Mutex;
+
+use crate::{
+    device::mmio::MmioHandler,
+    error::HvResult,
+    room::{self, RoomId},
+};
+
+use super::queue::{Virtqueue, QUEUE_SIZE_MAX};
+
+const MAGIC: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+/// "QHYP"
+const VENDOR_ID: u32 = 0x5059_4851;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_VENDOR_ID: usize = 0x00c;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const REG_CONFIG: usize = 0x100;
+
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 1 << 6;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A device model behind [`MmioTransport`].
+pub trait VirtioDevice: Send + Sync {
+    fn device_id(&self) -> u32;
+    /// Offered on top of [`VIRTIO_F_VERSION_1`].
+    fn features(&self) -> u64;
+    fn queue_count(&self) -> usize;
+
+    fn read_config(&self, _offset: usize, _width: usize) -> u64 {
+        0
+    }
+
+    fn write_config(&self, _offset: usize, _width: usize, _value: u64) {}
+
+    /// Serve what the driver made available in queue `index` of room `id`, returns whether
+    /// requests were used.
+    fn notify(&self, id: RoomId, index: usize, queue: &mut Virtqueue) -> HvResult<bool>;
+
+    /// Called after [`notify`](Self::notify) once the queues are unlocked, e.g. to hand
+    /// data over to other devices.
+    fn after_notify(&self, _id: RoomId) {}
+
+    /// The driver reset the device.
+    fn reset(&self) {}
+}
+
+/// Read `width` bytes at `offset` of a device config space laid out in `config`.
+pub fn read_config_bytes(config: &[u8], offset: usize, width: usize) -> u64 {
+    let mut value = [0u8; 8];
+    for (i, byte) in value.iter_mut().take(width).enumerate() {
+        *byte = config.get(offset + i).copied().unwrap_or(0);
+    }
+    u64::from_le_bytes(value)
+}
+
+#[derive(Default)]
+struct State {
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: usize,
+    queues: Vec<Virtqueue>,
+    interrupt_status: u32,
+}
+
+impl State {
+    fn new(queue_count: usize) -> Self {
+        Self {
+            queues: vec![Virtqueue::default(); queue_count],
+            ..Default::default()
+        }
+    }
+
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel)
+    }
+}
+
+fn set_low(reg: &mut u64, value: u64) {
+    *reg = *reg & !0xffff_ffff | value & 0xffff_ffff;
+}
+
+fn set_high(reg: &mut u64, value: u64) {
+    *reg = *reg & 0xffff_ffff | value << 32;
+}
+
+/// A virtio-mmio window of room `room` raising `irq`.
+pub struct MmioTransport<D> {
+    room: RoomId,
+    irq: u32,
+    pub device: D,
+    state: Mutex<State>,
+}
+
+impl<D: VirtioDevice> MmioTransport<D> {
+    pub fn new(room: RoomId, irq: u32, device: D) -> Self {
+        let state = State::new(device.queue_count());
+        Self {
+            room,
+            irq,
+            device,
+            state: Mutex::new(state),
+        }
+    }
+
+    fn offered(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    /// Run `f` on queue `index`, e.g. to fill receive buffers out of a notification of the
+    /// driver. Used requests raise the interrupt unless the driver suppressed it.
+    pub fn with_queue(
+        &self,
+        index: usize,
+        f: impl FnOnce(RoomId, &mut Virtqueue) -> HvResult<bool>,
+    ) -> HvResult {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        let Some(queue) = state.queues.get_mut(index) else {
+            return Ok(());
+        };
+        match f(self.room, queue) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => {
+                warn!("room {} virtio queue {}: {:?}", self.room, index, e);
+                state.status |= STATUS_DEVICE_NEEDS_RESET;
+                return Ok(());
+            }
+        }
+        if queue.wants_interrupt(self.room)? {
+            state.interrupt_status |= INTERRUPT_USED_BUFFER;
+            room::inject_irq(self.room, self.irq)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: VirtioDevice> MmioHandler for MmioTransport<D> {
+    fn read(&self, offset: usize, width: usize) -> HvResult<u64> {
+        if offset >= REG_CONFIG {
+            return Ok(self.device.read_config(offset - REG_CONFIG, width));
+        }
+        let mut state = self.state.lock();
+        let value = match offset {
+            REG_MAGIC => MAGIC,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match state.device_features_sel {
+                sel @ 0..=1 => (self.offered() >> (sel * 32)) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => state.queue().map_or(0, |_| QUEUE_SIZE_MAX as u32),
+            REG_QUEUE_READY => state.queue().map_or(0, |q| q.ready as u32),
+            REG_INTERRUPT_STATUS => state.interrupt_status,
+            REG_STATUS => state.status,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn write(&self, offset: usize, width: usize, value: u64) -> HvResult {
+        if offset >= REG_CONFIG {
+            self.device.write_config(offset - REG_CONFIG, width, value);
+            return Ok(());
+        }
+        let value32 = value as u32;
+        if offset == REG_QUEUE_NOTIFY {
+            let index = value32 as usize;
+            self.with_queue(index, |id, queue| self.device.notify(id, index, queue))?;
+            self.device.after_notify(self.room);
+            return Ok(());
+        }
+        let mut state = self.state.lock();
+        match offset {
+            REG_DEVICE_FEATURES_SEL => state.device_features_sel = value32,
+            REG_DRIVER_FEATURES_SEL => state.driver_features_sel = value32,
+            REG_DRIVER_FEATURES => match state.driver_features_sel {
+                0 => set_low(&mut state.driver_features, value),
+                1 => set_high(&mut state.driver_features, value),
+                _ => {}
+            },
+            REG_QUEUE_SEL => state.queue_sel = value32 as usize,
+            REG_INTERRUPT_ACK => state.interrupt_status &= !value32,
+            REG_STATUS if value32 == 0 => {
+                *state = State::new(self.device.queue_count());
+                self.device.reset();
+            }
+            REG_STATUS => {
+                let mut status = value32;
+                if status & STATUS_FEATURES_OK != 0 && state.driver_features & !self.offered() != 0
+                {
+                    status &= !STATUS_FEATURES_OK;
+                }
+                state.status = status;
+            }
+            _ => {
+                let Some(queue) = state.queue() else {
+                    return Ok(());
+                };
+                match offset {
+                    REG_QUEUE_NUM => queue.num = (value32 as u16).min(QUEUE_SIZE_MAX),
+                    REG_QUEUE_READY => queue.ready = value32 & 1 != 0,
+                    REG_QUEUE_DESC_LOW => set_low(&mut queue.desc, value),
+                    REG_QUEUE_DESC_HIGH => set_high(&mut queue.desc, value),
+                    REG_QUEUE_DRIVER_LOW => set_low(&mut queue.driver, value),
+                    REG_QUEUE_DRIVER_HIGH => set_high(&mut queue.driver, value),
+                    REG_QUEUE_DEVICE_LOW => set_low(&mut queue.device, value),
+                    REG_QUEUE_DEVICE_HIGH => set_high(&mut queue.device, value),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
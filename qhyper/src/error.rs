@@ -10,6 +10,7 @@ pub enum HvErrorNum {
     ENOENT = 2,
     EIO = 5,
     E2BIG = 7,
+    ENOEXEC = 8,
     ENOMEM = 12,
     EFAULT = 14,
     EBUSY = 16,
@@ -20,6 +21,8 @@ pub enum HvErrorNum {
     ENOSYS = 38,
 }
 
+pub type HvResult<T = ()> = core::result::Result<T, HvError>;
+
 pub struct HvError {
     pub num: HvErrorNum,
     pub loc_line: u32,
@@ -36,6 +39,7 @@ impl HvErrorNum {
             ENOENT => "No such file or directory",
             EIO => "I/O error",
             E2BIG => "Argument list too long",
+            ENOEXEC => "Exec format error",
             ENOMEM => "Out of memory",
             EFAULT => "Bad address",
             EBUSY => "Device or resource busy",
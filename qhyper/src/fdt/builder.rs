@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// Serializer of flattened device trees, nodes are written in order with
+/// [`begin_node`](Self::begin_node) / [`end_node`](Self::end_node).
+#[derive(Default)]
+pub struct FdtBuilder {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+    reserved: Vec<(u64, u64)>,
+    depth: usize,
+    boot_cpuid_phys: u32,
+}
+
+impl FdtBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an entry to the memory reservation block.
+    pub fn add_reserved(&mut self, addr: u64, size: u64) {
+        self.reserved.push((addr, size));
+    }
+
+    pub fn set_boot_cpuid_phys(&mut self, id: u32) {
+        self.boot_cpuid_phys = id;
+    }
+
+    /// Open a node, the root node is named `""`.
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "fdt: end_node without begin_node");
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_off = self.string_offset(name);
+        self.token(FDT_PROP);
+        self.structs
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structs.extend_from_slice(&name_off.to_be_bytes());
+        self.structs.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_str(&mut self, name: &str, value: &str) {
+        let mut data = Vec::with_capacity(value.len() + 1);
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+        self.property(name, &data);
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let data: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &data);
+    }
+
+    /// Write out the blob, every node must be closed.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "fdt: {} nodes not closed", self.depth);
+        self.token(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let rsvmap_size = (self.reserved.len() + 1) * 16;
+        let off_dt_struct = off_mem_rsvmap + rsvmap_size;
+        let off_dt_strings = off_dt_struct + self.structs.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid_phys,
+            self.strings.len() as u32,
+            self.structs.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        for &(addr, size) in self.reserved.iter().chain([(0, 0)].iter()) {
+            blob.extend_from_slice(&addr.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structs);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structs.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structs.len() % 4 != 0 {
+            self.structs.push(0);
+        }
+    }
+
+    /// Offset of `name` in the strings block, shared between properties of the same name.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+}
@@ -0,0 +1,302 @@
+//! Device trees handed to guests, derived from the host tree.
+
+use core::ops::Range;
+
+use alloc::{format, string::String, vec::Vec};
+use fdt_parser::{Fdt, Node};
+use log::debug;
+
+use crate::{arch::vmpidr, percpu::CPUHardId};
+
+mod builder;
+
+pub use builder::FdtBuilder;
+
+/// `compatible` of the `/hypervisor` node given to guests.
+pub const HYPERVISOR_COMPATIBLE: &str = "qclic,qhyper";
+
+/// What a guest owns of the machine, in the terms of the host tree.
+#[derive(Debug, Default)]
+pub struct GuestFdt<'a> {
+    /// Guest RAM in IPAs, replaces the `/memory` nodes.
+    pub memory: Vec<Range<usize>>,
+    /// Hardware ids of the cpus kept under `/cpus`, the `n`th is shown as vcpu `n` with
+    /// affinity [`vmpidr`]`(n)`.
+    pub cpus: Vec<CPUHardId>,
+    /// Full paths of the device nodes kept, every node with `reg` not listed here is dropped.
+    pub devices: Vec<&'a str>,
+    /// Replace `/chosen/bootargs`.
+    pub bootargs: Option<&'a str>,
+    /// Replace `/chosen/stdout-path`.
+    pub stdout_path: Option<&'a str>,
+    /// `linux,initrd-start` and `linux,initrd-end`, in IPAs.
+    pub initrd: Option<Range<usize>>,
+}
+
+/// Full path of the node `stdout-path` in `/chosen` points to, aliases resolved.
+pub fn stdout_path<'a>(fdt: &'a Fdt<'a>) -> Option<&'a str> {
+    let chosen = fdt.find_nodes("/chosen").next()?;
+    let path = chosen.find_property("stdout-path")?.str();
+    let path = path.split(':').next()?;
+    if path.starts_with('/') {
+        Some(path)
+    } else {
+        fdt.find_aliase(path)
+    }
+}
+
+/// Full paths and `reg` windows of the device nodes of the host tree, the nodes a guest tree
+/// keeps only when listed in [`GuestFdt::devices`].
+pub fn devices<'a>(fdt: &'a Fdt<'a>) -> Vec<(String, Vec<Range<usize>>)> {
+    let mut paths: Vec<String> = Vec::new();
+    let mut devices = Vec::new();
+    for node in fdt.all_nodes() {
+        paths.truncate(node.level - 1);
+        let path = match paths.last() {
+            None => String::from("/"),
+            Some(parent) if parent == "/" => format!("/{}", node.name),
+            Some(parent) => format!("{}/{}", parent, node.name),
+        };
+        let parent = paths.last().map(String::as_str).unwrap_or("");
+        let is_memory =
+            parent == "/" && (node.name == "memory" || node.name.starts_with("memory@"));
+        if is_device(&node) && !is_memory && !matches!(parent, "/cpus" | "/reserved-memory") {
+            let regs = node
+                .reg()
+                .into_iter()
+                .flatten()
+                .map(|r| r.address as usize..r.address as usize + r.size.unwrap_or(0))
+                .collect();
+            devices.push((path.clone(), regs));
+        }
+        paths.push(path);
+    }
+    devices
+}
+
+fn is_device(node: &Node) -> bool {
+    node.find_property("reg").is_some() && !node.compatibles().any(|c| c == "simple-bus")
+}
+
+impl GuestFdt<'_> {
+    /// Write the guest tree: the host tree with only this guest's memory, cpus and devices,
+    /// a rewritten `/chosen` and a `/hypervisor` node.
+    pub fn build(&self, host: &Fdt) -> Vec<u8> {
+        let mut fdt = FdtBuilder::new();
+        let boot_cpu = CPUHardId::from(host.boot_cpuid_phys() as usize);
+        let boot_vcpu = self.vcpu_of(boot_cpu).unwrap_or(0);
+        fdt.set_boot_cpuid_phys(vmpidr(boot_vcpu) as _);
+
+        for region in host.memory_reservation_block() {
+            let start = region.address as usize;
+            if self.in_memory(start..start + region.size) {
+                fdt.add_reserved(start as _, region.size as _);
+            }
+        }
+
+        let root = host.all_nodes().next();
+        let root_u32 = |name: &str| root.as_ref()?.find_property(name).map(|p| p.u32());
+        let address_cells = root_u32("#address-cells").unwrap_or(2);
+        let size_cells = root_u32("#size-cells").unwrap_or(1);
+        // The root interrupt controller is shared by every guest, with all its children.
+        let gic = root_u32("interrupt-parent");
+
+        // paths[level - 1] is the path of the open node at that level
+        let mut paths: Vec<String> = Vec::new();
+        let mut open = 0;
+        let mut skip_below: Option<usize> = None;
+        let mut keep_below: Option<usize> = None;
+        let mut has_chosen = false;
+
+        for node in host.all_nodes() {
+            let level = node.level;
+            while open >= level {
+                fdt.end_node();
+                open -= 1;
+            }
+            paths.truncate(level - 1);
+            match skip_below {
+                Some(l) if level > l => continue,
+                _ => skip_below = None,
+            }
+            if keep_below.is_some_and(|l| level <= l) {
+                keep_below = None;
+            }
+
+            let path = match paths.last() {
+                None => String::from("/"),
+                Some(parent) if parent == "/" => format!("/{}", node.name),
+                Some(parent) => format!("{}/{}", parent, node.name),
+            };
+            let parent = paths.last().map(String::as_str).unwrap_or("");
+
+            let is_gic = gic.is_some() && node.find_property("phandle").map(|p| p.u32()) == gic;
+            if !(keep_below.is_some() || is_gic || self.keep(&node, &path, parent)) {
+                debug!("guest fdt: drop {}", path);
+                skip_below = Some(level);
+                continue;
+            }
+            if is_gic && keep_below.is_none() {
+                keep_below = Some(level);
+            }
+
+            let vcpu = match parent {
+                "/cpus" => self.vcpu_node(&node),
+                _ => None,
+            };
+            match (level, vcpu) {
+                (1, _) => fdt.begin_node(""),
+                (_, Some(n)) => fdt.begin_node(&format!("cpu@{:x}", vmpidr(n))),
+                _ => fdt.begin_node(node.name),
+            }
+            open = level;
+
+            if path == "/chosen" {
+                has_chosen = true;
+                self.write_chosen(&mut fdt, &node);
+            } else if let Some(n) = vcpu {
+                self.write_vcpu(&mut fdt, &node, n);
+            } else {
+                for prop in node.propertys() {
+                    fdt.property(prop.name, prop.raw_value());
+                }
+            }
+            paths.push(path);
+        }
+
+        while open > 1 {
+            fdt.end_node();
+            open -= 1;
+        }
+
+        if let Some(first) = self.memory.first() {
+            let mut reg = Vec::new();
+            for range in &self.memory {
+                push_cells(&mut reg, range.start as _, address_cells);
+                push_cells(&mut reg, (range.end - range.start) as _, size_cells);
+            }
+            fdt.begin_node(&format!("memory@{:x}", first.start));
+            fdt.property_str("device_type", "memory");
+            fdt.property("reg", &reg);
+            fdt.end_node();
+        }
+
+        if !has_chosen {
+            fdt.begin_node("chosen");
+            self.write_chosen_props(&mut fdt, None, None);
+            fdt.end_node();
+        }
+
+        fdt.begin_node("hypervisor");
+        fdt.property_str("compatible", HYPERVISOR_COMPATIBLE);
+        fdt.property_str("method", "hvc");
+        fdt.end_node();
+
+        if open == 1 {
+            fdt.end_node();
+        }
+        fdt.finish()
+    }
+
+    fn keep(&self, node: &Node, path: &str, parent: &str) -> bool {
+        let name = node.name;
+        if node.level == 1 {
+            return true;
+        }
+        if parent == "/" && (name == "memory" || name.starts_with("memory@")) {
+            return false;
+        }
+        if parent == "/" && name == "hypervisor" {
+            return false;
+        }
+        if parent == "/cpus" {
+            if name == "cpu-map" {
+                return false;
+            }
+            if name == "cpu" || name.starts_with("cpu@") {
+                return self.vcpu_node(node).is_some();
+            }
+            return true;
+        }
+        if parent == "/reserved-memory" {
+            return match node.reg() {
+                Some(mut reg) => reg.all(|r| {
+                    let start = r.address as usize;
+                    self.in_memory(start..start + r.size.unwrap_or(0))
+                }),
+                None => true,
+            };
+        }
+
+        !is_device(node) || self.devices.contains(&path)
+    }
+
+    /// The vcpu shown for the physical cpu `cpu`, if the guest has it.
+    fn vcpu_of(&self, cpu: CPUHardId) -> Option<usize> {
+        self.cpus.iter().position(|&c| c == cpu)
+    }
+
+    /// The vcpu of a `/cpus/cpu@` node, if the guest has its cpu.
+    fn vcpu_node(&self, node: &Node) -> Option<usize> {
+        let reg = node.reg()?.next()?;
+        self.vcpu_of(CPUHardId::from(reg.address as usize))
+    }
+
+    /// The properties of the node of a kept cpu, with `reg` set to the affinity of vcpu `n`.
+    fn write_vcpu(&self, fdt: &mut FdtBuilder, host: &Node, n: usize) {
+        for prop in host.propertys() {
+            match prop.name {
+                "reg" => {
+                    let mut reg = Vec::new();
+                    push_cells(&mut reg, vmpidr(n), (prop.raw_value().len() / 4) as u32);
+                    fdt.property("reg", &reg);
+                }
+                _ => fdt.property(prop.name, prop.raw_value()),
+            }
+        }
+    }
+
+    fn write_chosen(&self, fdt: &mut FdtBuilder, host: &Node) {
+        for prop in host.propertys() {
+            match prop.name {
+                "bootargs" | "stdout-path" | "linux,stdout-path" | "linux,initrd-start"
+                | "linux,initrd-end" => {}
+                _ => fdt.property(prop.name, prop.raw_value()),
+            }
+        }
+        let bootargs = host.find_property("bootargs").map(|p| p.str());
+        let stdout_path = host.find_property("stdout-path").map(|p| p.str());
+        self.write_chosen_props(fdt, bootargs, stdout_path);
+    }
+
+    fn write_chosen_props(
+        &self,
+        fdt: &mut FdtBuilder,
+        bootargs: Option<&str>,
+        stdout_path: Option<&str>,
+    ) {
+        if let Some(bootargs) = self.bootargs.or(bootargs) {
+            fdt.property_str("bootargs", bootargs);
+        }
+        if let Some(stdout_path) = self.stdout_path.or(stdout_path) {
+            fdt.property_str("stdout-path", stdout_path);
+        }
+        if let Some(initrd) = &self.initrd {
+            fdt.property_u64("linux,initrd-start", initrd.start as _);
+            fdt.property_u64("linux,initrd-end", initrd.end as _);
+        }
+    }
+
+    fn in_memory(&self, range: Range<usize>) -> bool {
+        self.memory
+            .iter()
+            .any(|m| m.start <= range.start && range.end <= m.end)
+    }
+}
+
+fn push_cells(buf: &mut Vec<u8>, value: u64, cells: u32) {
+    match cells {
+        1 => buf.extend_from_slice(&(value as u32).to_be_bytes()),
+        _ => buf.extend_from_slice(&value.to_be_bytes()),
+    }
+}
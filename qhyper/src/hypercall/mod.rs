@@ -1,8 +1,13 @@
-use crate::device::virtio::VIRTIO_BRIDGE;
+use crate::arch::gic::vgic;
+use crate::device::virtio::bridge::{self, VIRTIO_BRIDGE};
 use crate::error::HvError;
-use crate::mem::PAGE_SIZE_4K;
 use crate::percpu::PerCpu;
+use crate::room::{self, RoomId, RoomInfo, ROOT_ROOM};
+use crate::{hv_err, hv_result_err};
+use alloc::{format, vec};
+use core::{mem::size_of, ptr::slice_from_raw_parts};
 use log::{debug, info, warn};
+use qhyper_config::CellConfig;
 numeric_enum_macro::numeric_enum! {
     #[repr(u64)]
     #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -12,19 +17,23 @@ numeric_enum_macro::numeric_enum! {
         CellStart = 2,
         CellOff = 3,
         CellList = 4,
+        CellCreate = 6,
         ClearInjectIrq = 20,
         IvcInfo = 5,
     }
 }
 
+/// Largest cell config accepted by `CellCreate`.
+const CELL_CONFIG_MAX: usize = 0x10000;
+
 pub type HyperCallResult = core::result::Result<usize, HvError>;
 
 pub struct HyperCall<'live> {
-    cpu_data: &'live mut PerCpu,
+    cpu_data: &'live PerCpu,
 }
 
 impl<'live> HyperCall<'live> {
-    pub fn new(cpu_data: &'live mut PerCpu) -> Self {
+    pub fn new(cpu_data: &'live PerCpu) -> Self {
         Self { cpu_data }
     }
 
@@ -32,8 +41,8 @@ impl<'live> HyperCall<'live> {
         let id = match HyperCallID::try_from(id) {
             Ok(id) => id,
             Err(_) => {
-                warn!("hypercall id={} unsupported!", id);
-                return Ok(0);
+                warn!("hypercall id={:#x} unsupported!", id);
+                return hv_result_err!(ENOSYS);
             }
         };
 
@@ -42,22 +51,105 @@ impl<'live> HyperCall<'live> {
         unsafe {
             match id {
                 HyperCallID::VirtioInit => self.hv_virtio_init(arg0),
+                HyperCallID::VirtioInjectIrq => self.hv_virtio_inject_irq(arg0, arg1),
+                HyperCallID::ClearInjectIrq => self.hv_clear_inject_irq(arg0),
+                HyperCallID::CellStart => self.hv_cell_start(arg0),
+                HyperCallID::CellOff => self.hv_cell_off(arg0),
+                HyperCallID::CellList => self.hv_cell_list(arg0, arg1),
+                HyperCallID::CellCreate => self.hv_cell_create(arg0, arg1),
                 _ => {
                     warn!("hypercall id={} unsupported!", id as u64);
-                    Ok(0)
+                    hv_result_err!(ENOSYS)
                 }
             }
         }
     }
 
+    /// The backend of the root room gives the page of the virtio rings.
     fn hv_virtio_init(&mut self, shared_region_addr: u64) -> HyperCallResult {
         info!(
             "handle hvc init virtio, shared_region_addr = {:#x?}",
             shared_region_addr
         );
+        self.check_root()?;
         let shared_region_addr_init = shared_region_addr as usize;
-        assert!(shared_region_addr_init % PAGE_SIZE_4K == 0);
+        bridge::check_region(shared_region_addr_init)?;
         VIRTIO_BRIDGE.lock().init_addr(shared_region_addr_init as _);
         HyperCallResult::Ok(0)
     }
+
+    /// Make `intid` pending in room `id`, for the device backends of the root room.
+    fn hv_virtio_inject_irq(&mut self, id: u64, intid: u64) -> HyperCallResult {
+        self.check_root()?;
+        room::inject_irq(id as _, intid as _)?;
+        Ok(0)
+    }
+
+    /// Withdraw `intid` from the caller if it was not taken yet.
+    fn hv_clear_inject_irq(&mut self, intid: u64) -> HyperCallResult {
+        self.caller()?;
+        vgic::clear(intid as _);
+        Ok(0)
+    }
+
+    /// The room of the calling guest.
+    fn caller(&self) -> Result<RoomId, HvError> {
+        self.cpu_data
+            .vcpu()
+            .map(|vcpu| vcpu.room)
+            .ok_or_else(|| hv_err!(EPERM, "hypercall outside of a room"))
+    }
+
+    fn check_root(&self) -> Result<(), HvError> {
+        match self.caller()? {
+            ROOT_ROOM => Ok(()),
+            id => hv_result_err!(EPERM, format!("room {} is not root", id)),
+        }
+    }
+
+    /// Create a room from the [`CellConfig`] blob of `size` bytes at `config_ipa`, returns
+    /// its id.
+    fn hv_cell_create(&mut self, config_ipa: u64, size: u64) -> HyperCallResult {
+        self.check_root()?;
+        if size as usize > CELL_CONFIG_MAX {
+            return hv_result_err!(E2BIG, format!("cell config too large: {:#x}", size));
+        }
+        let mut blob = vec![0u8; size as usize];
+        room::with(ROOT_ROOM, |room| {
+            room.space.copy_from_guest(config_ipa as _, &mut blob)
+        })??;
+        let config =
+            CellConfig::parse(&blob).map_err(|e| hv_err!(EINVAL, format!("cell config: {}", e)))?;
+        room::create_from_config(config)
+    }
+
+    fn hv_cell_start(&mut self, id: u64) -> HyperCallResult {
+        self.check_root()?;
+        room::start(id as _)?;
+        Ok(0)
+    }
+
+    fn hv_cell_off(&mut self, id: u64) -> HyperCallResult {
+        self.check_root()?;
+        if id as RoomId == ROOT_ROOM {
+            return hv_result_err!(EINVAL, "root room can not be stopped");
+        }
+        room::stop(id as _)?;
+        Ok(0)
+    }
+
+    /// Write as many [`RoomInfo`] as fit in the `size` bytes at `buf_ipa`, returns the
+    /// number of rooms.
+    fn hv_cell_list(&mut self, buf_ipa: u64, size: u64) -> HyperCallResult {
+        self.check_root()?;
+        let list = room::list();
+        let count = list.len().min(size as usize / size_of::<RoomInfo>());
+        let bytes = unsafe {
+            &*slice_from_raw_parts(list.as_ptr() as *const u8, count * size_of::<RoomInfo>())
+        };
+        room::with(self.caller()?, |room| {
+            room.space.copy_to_guest(buf_ipa as _, bytes)
+        })??;
+        Ok(list.len())
+    }
 }
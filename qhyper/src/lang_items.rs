@@ -1,11 +1,70 @@
-use core::panic::PanicInfo;
-
-use log::error;
-
-use crate::arch::shutdown;
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    error!("kernel panic: {:?}", info);
-    shutdown()
-}
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use log::{error, info};
+
+use crate::{
+    arch::{halt, reboot, shutdown},
+    mem::get_fdt,
+};
+
+/// What the panic handler does with the machine once the panic is reported.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    Halt = 0,
+    Reset = 1,
+    PowerOff = 2,
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::PowerOff as u8);
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::SeqCst);
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::SeqCst) {
+        0 => PanicAction::Halt,
+        1 => PanicAction::Reset,
+        _ => PanicAction::PowerOff,
+    }
+}
+
+/// Read the panic action from `qhyper.panic=halt|reset|poweroff` in `/chosen/bootargs`.
+pub fn init() {
+    let Some(fdt) = get_fdt() else {
+        return;
+    };
+    let Some(bootargs) = fdt.chosen().and_then(|chosen| chosen.bootargs()) else {
+        return;
+    };
+
+    for arg in bootargs.split_whitespace() {
+        if let Some(value) = arg.strip_prefix("qhyper.panic=") {
+            let action = match value {
+                "halt" => PanicAction::Halt,
+                "reset" => PanicAction::Reset,
+                "poweroff" => PanicAction::PowerOff,
+                _ => {
+                    error!("unknown panic action: {}", value);
+                    continue;
+                }
+            };
+            set_panic_action(action);
+        }
+    }
+    info!("panic action: {:?}", panic_action());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("kernel panic: {:?}", info);
+    match panic_action() {
+        PanicAction::Halt => halt(),
+        PanicAction::Reset => reboot(),
+        PanicAction::PowerOff => shutdown(),
+    }
+}
@@ -0,0 +1,148 @@
+//! Loader for arm64 Linux guests, following `Documentation/arch/arm64/booting.rst`.
+
+use core::ops::Range;
+
+use alloc::format;
+use log::{debug, info};
+use memory_addr::MemoryAddr;
+
+use crate::{
+    arch::cache::dcache_clean_invalidate,
+    error::HvResult,
+    hv_err, hv_result_err,
+    mem::{guest::GuestPhysSpace, PAGE_SIZE_2M, PAGE_SIZE_4K},
+};
+
+/// "ARM\x64"
+const IMAGE_MAGIC: u32 = 0x644d5241;
+const IMAGE_HEADER_SIZE: usize = 0x40;
+/// Kernels older than 3.17 have no `image_size` and are loaded at this offset.
+const LEGACY_TEXT_OFFSET: usize = 0x80000;
+const FLAG_BE: u64 = 0b1;
+/// The dtb must not exceed 2MiB.
+pub const DTB_MAX_SIZE: usize = PAGE_SIZE_2M;
+
+/// An arm64 kernel `Image` with a valid header.
+pub struct Image<'a> {
+    data: &'a [u8],
+    text_offset: usize,
+    image_size: usize,
+}
+
+impl<'a> Image<'a> {
+    pub fn parse(data: &'a [u8]) -> HvResult<Self> {
+        if data.len() < IMAGE_HEADER_SIZE {
+            return hv_result_err!(ENOEXEC, format!("image too small: {:#x}", data.len()));
+        }
+        let u32_at = |off: usize| u32::from_le_bytes(data[off..off + 4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_le_bytes(data[off..off + 8].try_into().unwrap());
+
+        let magic = u32_at(0x38);
+        if magic != IMAGE_MAGIC {
+            return hv_result_err!(ENOEXEC, format!("bad image magic: {:#x}", magic));
+        }
+        let flags = u64_at(0x18);
+        if flags & FLAG_BE != 0 {
+            return hv_result_err!(ENOEXEC, "big-endian image");
+        }
+
+        let (text_offset, image_size) = match u64_at(0x10) as usize {
+            0 => (LEGACY_TEXT_OFFSET, data.len()),
+            size => (u64_at(0x8) as usize, size.max(data.len())),
+        };
+
+        debug!(
+            "Image text_offset: {:#x}, image_size: {:#x}, flags: {:#x}",
+            text_offset, image_size, flags
+        );
+
+        Ok(Self {
+            data,
+            text_offset,
+            image_size,
+        })
+    }
+
+    pub fn text_offset(&self) -> usize {
+        self.text_offset
+    }
+
+    /// Memory the kernel uses from its load address, `.bss` included.
+    pub fn image_size(&self) -> usize {
+        self.image_size
+    }
+}
+
+/// Where a guest is placed in its RAM, in IPAs.
+///
+/// The kernel goes at the first 2MiB aligned base plus `text_offset`, the dtb gets the
+/// 2MiB aligned slot after it so it never crosses a 2MiB boundary, and the initrd follows
+/// that slot. The addresses are known before the dtb is built, so `/chosen` can point at
+/// the initrd.
+#[derive(Debug, Clone)]
+pub struct LinuxBoot {
+    pub entry: usize,
+    pub dtb: usize,
+    pub initrd: Option<Range<usize>>,
+}
+
+impl LinuxBoot {
+    pub fn plan(ram: Range<usize>, image: &Image, initrd_size: Option<usize>) -> HvResult<Self> {
+        let base = ram.start.align_up(PAGE_SIZE_2M);
+        let entry = base + image.text_offset;
+        let dtb = (entry + image.image_size).align_up(PAGE_SIZE_2M);
+        let initrd = initrd_size.map(|size| {
+            let start = dtb + DTB_MAX_SIZE;
+            start..start + size
+        });
+
+        let end = initrd.as_ref().map_or(dtb + DTB_MAX_SIZE, |r| r.end);
+        if end > ram.end {
+            return hv_result_err!(
+                ENOMEM,
+                format!("guest needs [{:#x}, {:#x}), ram is {:#x?}", base, end, ram)
+            );
+        }
+
+        Ok(Self { entry, dtb, initrd })
+    }
+
+    /// Copy everything in place, the guest starts with MMU and caches off so it is all
+    /// cleaned to the point of coherency.
+    pub fn load(
+        &self,
+        space: &GuestPhysSpace,
+        image: &Image,
+        initrd: Option<&[u8]>,
+        dtb: &[u8],
+    ) -> HvResult {
+        if dtb.len() > DTB_MAX_SIZE {
+            return hv_result_err!(E2BIG, format!("dtb too large: {:#x}", dtb.len()));
+        }
+        load(space, self.entry, image.data)?;
+        load(space, self.dtb, dtb)?;
+        if let (Some(range), Some(data)) = (&self.initrd, initrd) {
+            if data.len() != range.len() {
+                return hv_result_err!(EINVAL, "initrd size differs from the plan");
+            }
+            load(space, range.start, data)?;
+        }
+        info!("Linux loaded: {:#x?}", self);
+        Ok(())
+    }
+}
+
+fn load(space: &GuestPhysSpace, ipa: usize, data: &[u8]) -> HvResult {
+    space.copy_to_guest(ipa, data)?;
+
+    let mut done = 0;
+    while done < data.len() {
+        let ipa = ipa + done;
+        let len = (data.len() - done).min(PAGE_SIZE_4K - ipa % PAGE_SIZE_4K);
+        if let Some(paddr) = space.translate(ipa) {
+            dcache_clean_invalidate(paddr, len);
+        }
+        done += len;
+    }
+    Ok(())
+}
@@ -3,21 +3,30 @@
 #![feature(naked_functions)]
 #![feature(concat_idents)]
 
-use core::hint::spin_loop;
+use core::ptr::slice_from_raw_parts;
 
-use log::info;
+use alloc::{vec, vec::Vec};
+use error::HvResult;
+use fdt::GuestFdt;
+use loader::{Image, LinuxBoot};
+use log::{error, info};
+use mem::space::SPACE_SET;
+use memory_addr::{MemoryAddr, PhysAddrRange};
+use page_table_generic::{AccessSetting, CacheSetting};
 
 extern crate alloc;
 
 #[cfg_attr(target_arch = "aarch64", path = "arch/aarch64/mod.rs")]
 pub mod arch;
 pub mod debug;
-mod lang_items;
+pub mod lang_items;
+pub mod loader;
 #[macro_use]
 pub mod logger;
 pub mod consts;
 pub mod device;
 pub mod error;
+pub mod fdt;
 pub mod hypercall;
 pub mod io;
 pub mod mem;
@@ -31,11 +40,127 @@ pub fn vm_main() -> ! {
     logger::init();
     info!("VM start");
 
+    lang_items::init();
+
     mem::init();
 
     info!("mem setup ok");
 
-    loop {
-        spin_loop();
+    time::init();
+
+    arch::gic::init();
+    time::timer::init();
+
+    percpu::boot_secondary();
+
+    match mem::kernel_range() {
+        Some(kernel) => {
+            if let Err(e) = create_root_room(kernel, mem::initrd_range())
+                .and_then(|_| room::start(room::ROOT_ROOM))
+            {
+                error!("root room: {:?}", e);
+            }
+        }
+        None => info!("no root kernel in /chosen"),
     }
+
+    room::idle()
+}
+
+/// The root room boots on this cpu the Linux `Image` and initrd loaded by the bootloader,
+/// with the guest memory and the host devices identity mapped.
+fn create_root_room(kernel: PhysAddrRange, initrd: Option<PhysAddrRange>) -> HvResult {
+    let id = room::create("root", vec![percpu::cpu_data().id], vec![])?;
+
+    let host = mem::get_fdt().expect("no fdt");
+    let stdout = fdt::stdout_path(&host);
+    // The devices the hypervisor keeps to itself stay out, but the console is shared.
+    let devices: Vec<_> = fdt::devices(&host)
+        .into_iter()
+        .filter(|(path, regs)| {
+            Some(path.as_str()) == stdout
+                || !regs.iter().any(|reg| {
+                    SPACE_SET.iter().any(|space| {
+                        space.phys.start.as_usize() < reg.end
+                            && reg.start < space.phys.end.as_usize()
+                    })
+                })
+        })
+        .collect();
+
+    room::with(id, |room| {
+        for ram in mem::guest_ram() {
+            room.space.map(
+                ram.start.as_usize(),
+                ram.start.as_usize(),
+                ram.size(),
+                AccessSetting::Read | AccessSetting::Write | AccessSetting::Execute,
+                CacheSetting::Normal,
+            )?;
+        }
+        let uart = mem::debug_space().phys;
+        room.space.map(
+            uart.start.as_usize(),
+            uart.start.as_usize(),
+            uart.size(),
+            AccessSetting::Read | AccessSetting::Write,
+            CacheSetting::Device,
+        )?;
+        // Devices may share a page, and the console is mapped already.
+        for reg in devices.iter().flat_map(|(_, regs)| regs) {
+            let start = reg.start.align_down_4k();
+            let end = reg.end.align_up_4k();
+            room.space.map_holes(
+                start,
+                start,
+                end - start,
+                AccessSetting::Read | AccessSetting::Write,
+                CacheSetting::Device,
+            )?;
+            room.devices.push(start..end);
+        }
+
+        let image = Image::parse(unsafe { range_data(kernel) })?;
+        let initrd_data = initrd.map(|r| unsafe { range_data(r) });
+        let ram = mem::guest_ram()
+            .filter(|r| !r.contains_range(kernel) && !initrd.is_some_and(|i| r.contains_range(i)))
+            .max_by_key(|r| r.size())
+            .ok_or_else(|| hv_err!(ENOMEM, "no guest RAM for the root room"))?;
+        let ram = ram.start.as_usize()..ram.end.as_usize();
+        let boot = LinuxBoot::plan(ram.clone(), &image, initrd_data.map(<[u8]>::len))?;
+
+        let dtb = GuestFdt {
+            memory: vec![ram],
+            cpus: vec![arch::cpu_id()],
+            devices: devices.iter().map(|(path, _)| path.as_str()).collect(),
+            initrd: boot.initrd.clone(),
+            ..Default::default()
+        }
+        .build(&host);
+
+        boot.load(&room.space, &image, initrd_data, &dtb)?;
+        room.entry = boot.entry;
+        room.dtb = boot.dtb;
+        Ok(())
+    })?
+}
+
+/// The bytes the bootloader left at `range`.
+///
+/// # Safety
+///
+/// `range` must be memory the hypervisor does not use, as the images in `/chosen` are.
+unsafe fn range_data(range: PhysAddrRange) -> &'static [u8] {
+    unsafe { &*slice_from_raw_parts(range.start.as_usize() as *const u8, range.size()) }
+}
+
+pub fn secondary_main() -> ! {
+    arch::install_trap_vector();
+    arch::gic::init_cpu();
+    time::timer::init_cpu();
+
+    percpu::set_online();
+    info!("CPU {} online", percpu::cpu_data().id);
+
+    room::idle()
 }
@@ -0,0 +1,300 @@
+use core::alloc::Layout;
+
+use alloc::format;
+use log::debug;
+use page_table_generic::{Access, AccessSetting, CacheSetting, MapConfig, PTEArch, PTEGeneric};
+
+use crate::{
+    arch::mmu::{
+        activate_stage2, flush_guest_tlb, Stage2PageTableImpl, Stage2TableRef, STAGE2_IPA_BITS,
+    },
+    error::HvResult,
+    hv_err, hv_result_err,
+};
+
+use super::{mmu::HeapGuard, HEAP_ALLOCATOR, PAGE_SIZE_4K};
+
+const ENTRIES: usize = PAGE_SIZE_4K / size_of::<usize>();
+
+fn entry_size(level: usize) -> usize {
+    PAGE_SIZE_4K << (9 * (level - 1))
+}
+
+/// Stage-2 address space of a guest, translating its IPAs to host physical addresses.
+pub struct GuestPhysSpace {
+    vmid: usize,
+    table: Stage2TableRef<'static>,
+}
+
+impl GuestPhysSpace {
+    pub fn new(vmid: usize) -> HvResult<Self> {
+        let mut access = HeapGuard(HEAP_ALLOCATOR.lock());
+        let table = Stage2TableRef::create_empty(&mut access)
+            .map_err(|e| hv_err!(ENOMEM, format!("stage-2 table: {:?}", e)))?;
+        Ok(Self { vmid, table })
+    }
+
+    pub fn vmid(&self) -> usize {
+        self.vmid
+    }
+
+    /// Make this space the stage-2 translation of the current cpu.
+    pub fn activate(&self) {
+        activate_stage2(self.vmid, &self.table);
+    }
+
+    /// Map `[ipa, ipa + size)` to `[paddr, paddr + size)`, the range must not be mapped yet.
+    pub fn map(
+        &mut self,
+        ipa: usize,
+        paddr: usize,
+        size: usize,
+        access: AccessSetting,
+        cache: CacheSetting,
+    ) -> HvResult {
+        check_range(ipa, size)?;
+        if paddr % PAGE_SIZE_4K != 0 {
+            return hv_result_err!(EINVAL, format!("paddr {:#x} not aligned", paddr));
+        }
+        if self.is_mapped(ipa, ipa + size) {
+            return hv_result_err!(
+                EEXIST,
+                format!("ipa [{:#x}, {:#x}) already mapped", ipa, ipa + size)
+            );
+        }
+
+        debug!(
+            "vm {} map ipa [{:#x}, {:#x}) -> [{:#x}, {:#x})",
+            self.vmid,
+            ipa,
+            ipa + size,
+            paddr,
+            paddr + size
+        );
+
+        let mut heap = HeapGuard(HEAP_ALLOCATOR.lock());
+        unsafe {
+            self.table.map_region(
+                MapConfig::new(ipa as _, paddr, access, cache),
+                size,
+                true,
+                &mut heap,
+            )
+        }
+        .map_err(|e| hv_err!(ENOMEM, format!("stage-2 map: {:?}", e)))
+    }
+
+    /// Map the pages of `[ipa, ipa + size)` not mapped yet to those at the same offset from
+    /// `paddr`, the mapped ones are left as they are.
+    pub fn map_holes(
+        &mut self,
+        ipa: usize,
+        paddr: usize,
+        size: usize,
+        access: AccessSetting,
+        cache: CacheSetting,
+    ) -> HvResult {
+        check_range(ipa, size)?;
+        let end = ipa + size;
+        let mut at = ipa;
+        while at < end {
+            let start = at;
+            while at < end && self.translate(at).is_none() {
+                at += PAGE_SIZE_4K;
+            }
+            if start == at {
+                at += PAGE_SIZE_4K;
+                continue;
+            }
+            self.map(start, paddr + (start - ipa), at - start, access, cache)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the mappings in `[ipa, ipa + size)`, blocks crossing the bounds are split.
+    pub fn unmap(&mut self, ipa: usize, size: usize) -> HvResult {
+        check_range(ipa, size)?;
+        debug!("vm {} unmap ipa [{:#x}, {:#x})", self.vmid, ipa, ipa + size);
+        self.update_leaves(ipa, ipa + size, |_| 0)
+    }
+
+    /// Change the access permission of the mappings in `[ipa, ipa + size)`.
+    pub fn protect(&mut self, ipa: usize, size: usize, access: AccessSetting) -> HvResult {
+        check_range(ipa, size)?;
+        debug!(
+            "vm {} protect ipa [{:#x}, {:#x}) {:?}",
+            self.vmid,
+            ipa,
+            ipa + size,
+            access.bits()
+        );
+        self.update_leaves(ipa, ipa + size, |raw| {
+            let mut pte = Stage2PageTableImpl::read_pte(raw);
+            pte.setting.privilege_access = access;
+            Stage2PageTableImpl::new_pte(pte)
+        })
+    }
+
+    /// The host physical address `ipa` is mapped to.
+    pub fn translate(&self, ipa: usize) -> Option<usize> {
+        let (ptr, level) = self.walk(ipa);
+        let pte = Stage2PageTableImpl::read_pte(unsafe { ptr.read_volatile() });
+        if !pte.is_valid {
+            return None;
+        }
+        Some(pte.paddr + ipa % entry_size(level))
+    }
+
+    /// Copy `data` into guest memory at `ipa`, the pages may be scattered in host memory.
+    pub fn copy_to_guest(&self, ipa: usize, data: &[u8]) -> HvResult {
+        let mut done = 0;
+        while done < data.len() {
+            let (paddr, len) = self.chunk(ipa + done, data.len() - done)?;
+            unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), paddr as *mut u8, len) };
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Fill `buf` from guest memory at `ipa`.
+    pub fn copy_from_guest(&self, ipa: usize, buf: &mut [u8]) -> HvResult {
+        let mut done = 0;
+        while done < buf.len() {
+            let (paddr, len) = self.chunk(ipa + done, buf.len() - done)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(paddr as *const u8, buf[done..].as_mut_ptr(), len)
+            };
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// The host address of `ipa` and how many bytes of `len` are contiguous from there,
+    /// bounded by the page.
+    fn chunk(&self, ipa: usize, len: usize) -> HvResult<(usize, usize)> {
+        let paddr = self
+            .translate(ipa)
+            .ok_or_else(|| hv_err!(EFAULT, format!("ipa {:#x} not mapped", ipa)))?;
+        Ok((paddr, len.min(PAGE_SIZE_4K - ipa % PAGE_SIZE_4K)))
+    }
+
+    fn is_mapped(&self, start: usize, end: usize) -> bool {
+        let mut ipa = start;
+        while ipa < end {
+            let (ptr, level) = self.walk(ipa);
+            if Stage2PageTableImpl::read_pte(unsafe { ptr.read_volatile() }).is_valid {
+                return true;
+            }
+            ipa = (ipa & !(entry_size(level) - 1)) + entry_size(level);
+        }
+        false
+    }
+
+    /// Find the entry translating `ipa`, stopping at the first leaf or invalid entry.
+    fn walk(&self, ipa: usize) -> (*mut usize, usize) {
+        let mut table = self.table.paddr();
+        let mut level = Stage2PageTableImpl::level();
+        loop {
+            let idx = (ipa / entry_size(level)) % ENTRIES;
+            let ptr = unsafe { (table as *mut usize).add(idx) };
+            let pte = Stage2PageTableImpl::read_pte(unsafe { ptr.read_volatile() });
+            if level == 1 || !pte.is_valid || pte.is_block {
+                return (ptr, level);
+            }
+            table = pte.paddr;
+            level -= 1;
+        }
+    }
+
+    /// Rewrite every leaf entry in `[start, end)` with `f`, invalidating each one in the TLB.
+    fn update_leaves(&mut self, start: usize, end: usize, f: impl Fn(usize) -> usize) -> HvResult {
+        let mut ipa = start;
+        while ipa < end {
+            let (ptr, level) = self.walk(ipa);
+            let size = entry_size(level);
+            let base = ipa & !(size - 1);
+            let raw = unsafe { ptr.read_volatile() };
+
+            if !Stage2PageTableImpl::read_pte(raw).is_valid {
+                ipa = base + size;
+                continue;
+            }
+
+            if base < start || base + size > end {
+                self.split_block(ptr, base, level)?;
+                continue;
+            }
+
+            unsafe { ptr.write_volatile(f(raw)) };
+            flush_guest_tlb(self.vmid, &self.table, Some(base));
+            ipa = base + size;
+        }
+        Ok(())
+    }
+
+    /// Replace the block entry at `ptr` with a table of the next level mapping the same range.
+    fn split_block(&mut self, ptr: *mut usize, base: usize, level: usize) -> HvResult {
+        let block = Stage2PageTableImpl::read_pte(unsafe { ptr.read_volatile() });
+        let sub_level = level - 1;
+
+        let sub = unsafe {
+            HeapGuard(HEAP_ALLOCATOR.lock()).alloc(Layout::from_size_align_unchecked(
+                PAGE_SIZE_4K,
+                PAGE_SIZE_4K,
+            ))
+        }
+        .ok_or_else(|| hv_err!(ENOMEM, "stage-2 split"))?
+        .as_ptr() as *mut usize;
+
+        for i in 0..ENTRIES {
+            let pte = PTEGeneric {
+                paddr: block.paddr + i * entry_size(sub_level),
+                is_block: sub_level > 1,
+                is_valid: true,
+                setting: block.setting,
+            };
+            unsafe { sub.add(i).write(Stage2PageTableImpl::new_pte(pte)) };
+        }
+
+        // break-before-make
+        unsafe { ptr.write_volatile(0) };
+        flush_guest_tlb(self.vmid, &self.table, Some(base));
+
+        let table = PTEGeneric {
+            paddr: sub as usize,
+            is_block: false,
+            is_valid: true,
+            setting: block.setting,
+        };
+        unsafe { ptr.write_volatile(Stage2PageTableImpl::new_pte(table)) };
+        Ok(())
+    }
+}
+
+impl Drop for GuestPhysSpace {
+    fn drop(&mut self) {
+        flush_guest_tlb(self.vmid, &self.table, None);
+        let mut heap = HeapGuard(HEAP_ALLOCATOR.lock());
+        self.table.release(&mut heap);
+    }
+}
+
+fn check_range(ipa: usize, size: usize) -> HvResult {
+    if ipa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!("ipa range {:#x}+{:#x} not aligned", ipa, size)
+        );
+    }
+    if ipa
+        .checked_add(size)
+        .filter(|&end| end <= 1 << STAGE2_IPA_BITS)
+        .is_none()
+    {
+        return hv_result_err!(
+            ERANGE,
+            format!("ipa range {:#x}+{:#x} out of stage-2", ipa, size)
+        );
+    }
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use buddy_system_allocator::Heap;
 use log::debug;
 pub use page_table_generic::PTEGeneric;
-use page_table_generic::{Access, MapConfig};
+use page_table_generic::{Access, AccessSetting, CacheSetting, MapConfig};
 use spin::MutexGuard;
 
 use crate::{
@@ -10,7 +10,7 @@ use crate::{
     percpu::cpu_data,
 };
 
-use super::{space::Space, HEAP_ALLOCATOR};
+use super::{guest_ram, space::Space, HEAP_ALLOCATOR};
 
 pub fn init() {
     let data = cpu_data();
@@ -24,10 +24,25 @@ pub fn init() {
         map_space(&mut table, space, &mut access);
     }
 
+    // Guest memory is reachable by the hypervisor to load images and emulate devices.
+    for phys in guest_ram() {
+        map_space(
+            &mut table,
+            &Space {
+                name: "guest",
+                phys,
+                offset: 0,
+                access: AccessSetting::Read | AccessSetting::Write,
+                cache: CacheSetting::Normal,
+            },
+            &mut access,
+        );
+    }
+
     set_table(table);
 }
 
-struct HeapGuard<'a>(MutexGuard<'a, Heap<32>>);
+pub(super) struct HeapGuard<'a>(pub(super) MutexGuard<'a, Heap<32>>);
 
 impl Access for HeapGuard<'_> {
     fn va_offset(&self) -> usize {
@@ -5,19 +5,21 @@ use core::{
 
 use arrayvec::ArrayVec;
 use buddy_system_allocator::LockedHeap;
-use fdt_parser::Fdt;
+use fdt_parser::{Fdt, Node};
 use log::info;
 use memory_addr::{pa_range, MemoryAddr, PhysAddrRange};
+use once::OnceStatic;
 use page_table_generic::{AccessSetting, CacheSetting};
 use space::{Space, SPACE_SET};
 
 use crate::{
     arch::{self, is_mmu_enabled},
-    consts::KERNEL_STACK_SIZE,
-    debug, percpu,
+    consts::{HEAP_SIZE, KERNEL_STACK_SIZE},
+    debug, percpu, time,
 };
 
 pub mod addr;
+pub mod guest;
 pub mod mmu;
 pub mod once;
 pub mod space;
@@ -28,6 +30,7 @@ static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::<32>::empty();
 static VM_VA_OFFSET: AtomicUsize = AtomicUsize::new(111);
 static FDT_ADDR: AtomicPtr<u8> = AtomicPtr::new(null_mut());
 static FDT_LEN: AtomicUsize = AtomicUsize::new(0);
+static GUEST_RAM: OnceStatic<ArrayVec<PhysAddrRange, 16>> = OnceStatic::new(ArrayVec::new_const());
 
 const KERNEL_STACK_BOTTOM: usize = 0xE10000000000;
 /// The size of a page.
@@ -43,7 +46,13 @@ pub fn init() {
         .expect("no space")
         .end
         .as_usize();
-    let mut inited = false;
+    let mut images: ArrayVec<_, 2> = kernel_range()
+        .into_iter()
+        .chain(initrd_range())
+        .map(|r| r.start.as_usize().align_down_4k()..r.end.as_usize().align_up_4k())
+        .collect();
+    images.sort_unstable_by_key(|r| r.start);
+    let mut heap_left = HEAP_SIZE;
 
     //TODO 非设备树平台
     let fdt = get_fdt().unwrap();
@@ -56,36 +65,106 @@ pub fn init() {
                 start = memory_used_end.align_up_4k();
             }
 
-            let size = end - start;
-            info!(
-                "Add memory region [{:#x} - {:#x}), size: {:#x}",
-                start,
-                start + size,
-                size
-            );
-
-            unsafe {
-                if inited {
-                    HEAP_ALLOCATOR.lock().add_to_heap(start, size);
-                } else {
-                    HEAP_ALLOCATOR.lock().init(start, size);
-                    inited = true;
+            // The guest images loaded by the bootloader must not become heap.
+            let mut free = ArrayVec::<PhysAddrRange, 3>::new();
+            let mut at = start;
+            for image in &images {
+                let image_start = image.start.max(at);
+                let image_end = image.end.min(end);
+                if image_start >= image_end {
+                    continue;
+                }
+                free.push(pa_range!(at..image_start));
+                unsafe { (*GUEST_RAM.get()).push(pa_range!(image_start..image_end)) };
+                at = image_end;
+            }
+            free.push(pa_range!(at..end));
+
+            for range in free.into_iter().filter(|r| !r.is_empty()) {
+                let heap_size = heap_left.min(range.size());
+                let heap_end = range.start.as_usize() + heap_size;
+                if heap_size > 0 {
+                    add_heap(pa_range!(range.start.as_usize()..heap_end));
+                    heap_left -= heap_size;
+                }
+                if heap_end < range.end.as_usize() {
+                    let guest = pa_range!(heap_end..range.end.as_usize());
+                    info!("Guest memory region {:?}", guest);
+                    unsafe { (*GUEST_RAM.get()).push(guest) };
                 }
-
-                SPACE_SET.push(Space {
-                    name: "heap",
-                    phys: pa_range!(start..end),
-                    offset: 0,
-                    access: AccessSetting::Read | AccessSetting::Execute | AccessSetting::Write,
-                    cache: CacheSetting::Normal,
-                });
             }
         }
     }
+    unsafe { SPACE_SET.push(text_idmap_space()) };
+    arch::gic::probe();
+    for space in arch::gic::spaces() {
+        unsafe { SPACE_SET.push(space) };
+    }
+    time::probe();
+    if let Some(space) = time::space() {
+        unsafe { SPACE_SET.push(space) };
+    }
     percpu::init();
     mmu::init();
 }
 
+fn add_heap(range: PhysAddrRange) {
+    let start = range.start.as_usize();
+    let end = range.end.as_usize();
+    info!(
+        "Add memory region [{:#x} - {:#x}), size: {:#x}",
+        start,
+        end,
+        range.size()
+    );
+
+    unsafe {
+        HEAP_ALLOCATOR.lock().add_to_heap(start, end);
+
+        SPACE_SET.push(Space {
+            name: "heap",
+            phys: range,
+            offset: 0,
+            access: AccessSetting::Read | AccessSetting::Execute | AccessSetting::Write,
+            cache: CacheSetting::Normal,
+        });
+    }
+}
+
+/// Memory not used by the hypervisor, left to guests.
+pub fn guest_ram() -> impl Iterator<Item = PhysAddrRange> {
+    GUEST_RAM.iter().copied()
+}
+
+/// The kernel `Image` of the root room loaded by the bootloader, given by
+/// `qhyper,kernel-start` and `qhyper,kernel-end` in `/chosen`. Kept from the heap like the
+/// initrd.
+pub fn kernel_range() -> Option<PhysAddrRange> {
+    chosen_range("qhyper,kernel-start", "qhyper,kernel-end")
+}
+
+/// The initrd loaded by the bootloader, given by `/chosen` in the fdt. The range is exact,
+/// the pages around it are kept from the heap as guest RAM of its own.
+pub fn initrd_range() -> Option<PhysAddrRange> {
+    chosen_range("linux,initrd-start", "linux,initrd-end")
+}
+
+fn chosen_range(start: &str, end: &str) -> Option<PhysAddrRange> {
+    let fdt = get_fdt()?;
+    let chosen = fdt.find_nodes("/chosen").next()?;
+    let start = prop_usize(&chosen, start)?;
+    let end = prop_usize(&chosen, end)?;
+    Some(pa_range!(start..end))
+}
+
+fn prop_usize(node: &Node, name: &str) -> Option<usize> {
+    let prop = node.find_property(name)?;
+    Some(match prop.raw_value().len() {
+        4 => prop.u32() as usize,
+        _ => prop.u64() as usize,
+    })
+}
+
 pub(crate) unsafe fn save_fdt<'a>(ptr: *mut u8) -> Option<Fdt<'a>> {
     let stack_top = boot_stack().as_ptr_range().end;
     let fdt = fdt_parser::Fdt::from_ptr(NonNull::new(ptr)?).ok()?;
@@ -249,6 +328,17 @@ pub fn stack0() -> PhysAddrRange {
     let offset = if is_mmu_enabled() { va_offset() } else { 0 };
     slice_to_phys_range(boot_stack(), offset)
 }
+/// Identity mapping of `.text`, secondary cpus enable the MMU while running at physical addresses.
+pub fn text_idmap_space() -> Space {
+    Space {
+        name: "idmap",
+        phys: slice_to_phys_range(text(), va_offset()),
+        offset: 0,
+        access: AccessSetting::Read | AccessSetting::Execute,
+        cache: CacheSetting::Normal,
+    }
+}
+
 pub fn debug_space() -> Space {
     Space {
         name: "debug",
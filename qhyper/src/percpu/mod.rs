@@ -1,18 +1,41 @@
-use core::{alloc::Layout, fmt::Display};
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    fmt::Display,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use alloc::collections::btree_map::BTreeMap;
-use log::debug;
+use alloc::{
+    collections::{btree_map::BTreeMap, VecDeque},
+    vec::Vec,
+};
+use log::{debug, error, info};
 use memory_addr::{pa_range, PhysAddrRange};
+use spin::Mutex;
 
 use crate::{
+    arch::{
+        self,
+        gic::{self, vgic::Virq},
+        VCpu,
+    },
     consts::STACK_SIZE,
-    mem::{get_fdt, once::OnceStatic, stack0},
+    mem::{get_fdt, once::OnceStatic, stack, stack0},
+    room::RoomId,
+    time::timer::TimerEvent,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct CPUHardId(usize);
 
+impl CPUHardId {
+    pub fn raw(&self) -> usize {
+        self.0
+    }
+}
+
 impl From<usize> for CPUHardId {
     fn from(value: usize) -> Self {
         Self(value)
@@ -23,6 +46,34 @@ impl From<usize> for CPUHardId {
 #[repr(transparent)]
 pub struct CPUId(usize);
 
+impl CPUId {
+    pub fn raw(&self) -> usize {
+        self.0
+    }
+}
+
+impl From<usize> for CPUId {
+    fn from(value: usize) -> Self {
+        Self(value)
+    }
+}
+
+/// Work handed to a cpu by another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuRequest {
+    /// Boot the vcpu of a room.
+    Run(RoomId),
+    /// Start a secondary vcpu of a room at `entry` with `x0` = `context`, for a guest
+    /// `CPU_ON`.
+    CpuOn {
+        room: RoomId,
+        entry: usize,
+        context: usize,
+    },
+    /// Leave the current room.
+    Park,
+}
+
 impl Display for CPUHardId {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:#x}", self.0)
@@ -42,6 +93,69 @@ static PER_CPU: OnceStatic<BTreeMap<CPUId, PerCpu>> = OnceStatic::new(BTreeMap::
 pub struct PerCpu {
     pub id: CPUId,
     pub stack: PhysAddrRange,
+    pub online: AtomicBool,
+    vcpu: UnsafeCell<Option<VCpu>>,
+    /// Whether `vcpu` is set, for the other cpus.
+    has_vcpu: AtomicBool,
+    request: Mutex<Option<CpuRequest>>,
+    /// Virtual interrupts waiting for a list register.
+    pub virqs: Mutex<VecDeque<Virq>>,
+    /// Hypervisor timers of this cpu, sorted by deadline.
+    pub timers: Mutex<Vec<TimerEvent>>,
+}
+
+impl PerCpu {
+    /// The top of this cpu's stack, at the address it runs with.
+    pub fn stack_top(&self) -> usize {
+        if self.id == CPUId(0) {
+            stack().as_ptr_range().end as usize
+        } else {
+            self.stack.end.as_usize()
+        }
+    }
+
+    /// The vcpu running on this cpu, only touched by the cpu itself.
+    #[allow(clippy::mut_from_ref)]
+    pub fn vcpu(&self) -> Option<&mut VCpu> {
+        unsafe { (*self.vcpu.get()).as_mut() }
+    }
+
+    pub fn clear_vcpu(&self) {
+        unsafe { *self.vcpu.get() = None };
+        self.has_vcpu.store(false, Ordering::Release);
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub fn set_vcpu(&self, vcpu: VCpu) -> &mut VCpu {
+        self.has_vcpu.store(true, Ordering::Release);
+        unsafe { (*self.vcpu.get()).insert(vcpu) }
+    }
+
+    /// Whether this cpu runs a vcpu, from any cpu.
+    pub fn has_vcpu(&self) -> bool {
+        self.has_vcpu.load(Ordering::Acquire)
+    }
+
+    /// Hand `request` to this cpu, it is kicked out of its guest to take it.
+    pub fn post(&self, request: CpuRequest) {
+        *self.request.lock() = Some(request);
+        if self.id != cpu_data().id {
+            let _ = gic::send_sgi(self.id.into(), gic::SGI_KICK);
+        }
+    }
+
+    pub fn take_request(&self) -> Option<CpuRequest> {
+        self.request.lock().take()
+    }
+
+    /// The request waiting for this cpu, left in place.
+    pub fn peek_request(&self) -> Option<CpuRequest> {
+        *self.request.lock()
+    }
+
+    pub fn park_requested(&self) -> bool {
+        *self.request.lock() == Some(CpuRequest::Park)
+    }
 }
 
 impl From<CPUHardId> for CPUId {
@@ -85,7 +199,19 @@ pub fn init() {
                 let stack = stack as usize;
                 pa_range!(stack..stack + STACK_SIZE)
             };
-            (*PER_CPU.get()).insert(id, PerCpu { id, stack });
+            (*PER_CPU.get()).insert(
+                id,
+                PerCpu {
+                    id,
+                    stack,
+                    online: AtomicBool::new(id == CPUId(0)),
+                    vcpu: UnsafeCell::new(None),
+                    has_vcpu: AtomicBool::new(false),
+                    request: Mutex::new(None),
+                    virqs: Mutex::new(VecDeque::new()),
+                    timers: Mutex::new(Vec::new()),
+                },
+            );
         }
     }
 
@@ -98,3 +224,46 @@ pub fn cpu_data() -> &'static PerCpu {
         &(*PER_CPU.get())[&id]
     }
 }
+
+pub fn cpu_exists(id: CPUId) -> bool {
+    PER_CPU.contains_key(&id)
+}
+
+pub fn get_cpu_data(id: CPUId) -> &'static PerCpu {
+    &PER_CPU[&id]
+}
+
+/// Start every cpu found in the fdt except the current one, and wait until it reports ready.
+pub fn boot_secondary() {
+    let this = cpu_data().id;
+
+    for cpu in PER_CPU.values() {
+        if cpu.id == this {
+            continue;
+        }
+        let hard_id = CPUHardId::from(cpu.id);
+        debug!("Start cpu {} [{}], stack: {:?}", cpu.id, hard_id, cpu.stack);
+
+        if let Err(e) = arch::cpu_on(hard_id, cpu.stack) {
+            error!("{:?}", e);
+            continue;
+        }
+
+        while !cpu.online.load(Ordering::Acquire) {
+            spin_loop();
+        }
+    }
+
+    info!(
+        "{} cpus online",
+        PER_CPU
+            .values()
+            .filter(|cpu| cpu.online.load(Ordering::Acquire))
+            .count()
+    );
+}
+
+/// Mark the current cpu as under hypervisor control.
+pub fn set_online() {
+    cpu_data().online.store(true, Ordering::Release);
+}
@@ -1 +1,629 @@
-pub struct Room {}
+//! Rooms (cells): partitions of the machine, each with its own guest memory, cpus and irqs.
+//!
+//! Room [`ROOT_ROOM`] is the root cell started at boot, it manages the others through
+//! hypercalls.
+
+use core::ops::Range;
+
+use alloc::{collections::btree_map::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use log::{error, info, warn};
+use page_table_generic::{AccessSetting, CacheSetting};
+use qhyper_config::{overlaps, CellConfig, ConfigError, DeviceKind, MemFlags};
+use spin::Mutex;
+
+use crate::{
+    arch::{
+        self,
+        gic::{
+            self, vdist,
+            vgic::{self, Virq},
+        },
+        VCpu,
+    },
+    device::{
+        mmio::{MmioBus, MmioHandler},
+        virtio::{
+            blk::Block, bridge::VirtioMmio, console::Console, net::Net, switch,
+            transport::MmioTransport,
+        },
+    },
+    error::HvResult,
+    hv_err, hv_result_err,
+    mem::{self, guest::GuestPhysSpace, space::SPACE_SET},
+    percpu::{cpu_data, cpu_exists, get_cpu_data, CPUId, CpuRequest},
+};
+
+pub type RoomId = usize;
+
+pub const ROOT_ROOM: RoomId = 0;
+
+/// SPI injected into the root room when a room waits in [`RoomState::Reset`] for it, no
+/// device raises it.
+pub const ROOM_RESET_IRQ: u32 = 32 + 0x21;
+
+static ROOMS: Mutex<BTreeMap<RoomId, Room>> = Mutex::new(BTreeMap::new());
+
+numeric_enum_macro::numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum RoomState {
+        Created = 0,
+        Running = 1,
+        Stopped = 2,
+        Failed = 3,
+        /// Reset by its guest, the root room loads its images again and starts it.
+        Reset = 4,
+    }
+}
+
+pub struct Room {
+    pub id: RoomId,
+    pub name: String,
+    pub state: RoomState,
+    pub space: GuestPhysSpace,
+    /// The first cpu boots the room, the others wait for the guest to bring them up.
+    pub cpus: Vec<CPUId>,
+    pub irqs: Vec<u32>,
+    /// Host device windows identity mapped outside a cell config, those of the host tree for
+    /// the root room. Cells take theirs away from it.
+    pub devices: Vec<Range<usize>>,
+    /// Where the boot cpu starts, in IPA.
+    pub entry: usize,
+    /// The dtb given to the boot cpu in `x0`, in IPA.
+    pub dtb: usize,
+    /// What the room was created from, `None` for the root room.
+    pub config: Option<CellConfig>,
+    pub mmio: MmioBus,
+    /// The physical count when the room started, its guest counts from zero.
+    pub cntvoff: u64,
+}
+
+/// Entry of the list written by the `CellList` hypercall.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RoomInfo {
+    pub id: u32,
+    pub state: u32,
+    /// Bit `n` set if cpu `n` belongs to the room.
+    pub cpu_mask: u64,
+}
+
+/// Register a room owning `cpus`, its guest memory is mapped afterwards with [`with`].
+pub fn create(name: &str, cpus: Vec<CPUId>, irqs: Vec<u32>) -> HvResult<RoomId> {
+    insert(&mut ROOMS.lock(), name, cpus, irqs)
+}
+
+fn insert(
+    rooms: &mut BTreeMap<RoomId, Room>,
+    name: &str,
+    cpus: Vec<CPUId>,
+    irqs: Vec<u32>,
+) -> HvResult<RoomId> {
+    if cpus.is_empty() {
+        return hv_result_err!(EINVAL, format!("room {} has no cpu", name));
+    }
+    for room in rooms.values() {
+        if let Some(cpu) = cpus.iter().find(|c| room.cpus.contains(c)) {
+            return hv_result_err!(EBUSY, format!("cpu {} already in room {}", cpu, room.name));
+        }
+        if let Some(irq) = irqs.iter().find(|i| room.irqs.contains(i)) {
+            return hv_result_err!(EBUSY, format!("irq {} already in room {}", irq, room.name));
+        }
+    }
+
+    let id = rooms.keys().next_back().map_or(ROOT_ROOM, |id| id + 1);
+    let mut space = GuestPhysSpace::new(id + 1)?;
+    vgic::map_cpu_interface(&mut space)?;
+    let mut mmio = MmioBus::default();
+    vdist::register(id, &mut mmio)?;
+    info!("Room {} [{}] created, cpus: {:?}", id, name, cpus);
+    rooms.insert(
+        id,
+        Room {
+            id,
+            name: String::from(name),
+            state: RoomState::Created,
+            space,
+            cpus,
+            irqs,
+            devices: Vec::new(),
+            entry: 0,
+            dtb: 0,
+            config: None,
+            mmio,
+            cntvoff: 0,
+        },
+    );
+    Ok(id)
+}
+
+/// Create a room from a cell config given by the root room. Its memory and devices are taken
+/// away from the root room, except regions shared with it and loadable ones, which stay until
+/// the room starts.
+pub fn create_from_config(config: CellConfig) -> HvResult<RoomId> {
+    let config_err = |e: ConfigError| hv_err!(EINVAL, format!("cell {}: {}", config.name, e));
+
+    // The region ends below are only sound on a valid config.
+    config.validate().map_err(config_err)?;
+    if let Some(cpu) = config.cpus().find(|&cpu| !cpu_exists(cpu.into())) {
+        return hv_result_err!(ENODEV, format!("cell {}: no cpu {}", config.name, cpu));
+    }
+
+    // Checked and taken at once, so concurrent creations can not both pass.
+    let mut rooms = ROOMS.lock();
+    let windows = config
+        .memory_regions
+        .iter()
+        .enumerate()
+        .map(|(i, r)| (format!("memory region {}", i), (r.phys_start, r.phys_end())))
+        .chain(
+            (0..config.devices.len())
+                .filter(|&i| config.devices[i].kind == DeviceKind::Mmio)
+                .map(|i| (format!("device {}", i), config.device_window(i))),
+        )
+        .chain(
+            config
+                .console_window()
+                .map(|w| (String::from("console"), w)),
+        );
+    for (what, (start, end)) in windows {
+        if let Some(space) = SPACE_SET.iter().find(|space| {
+            overlaps(
+                start,
+                end,
+                space.phys.start.as_usize() as _,
+                space.phys.end.as_usize() as _,
+            )
+        }) {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "cell {}: {} overlaps hypervisor {}",
+                    config.name, what, space.name
+                )
+            );
+        }
+    }
+
+    for room in rooms.values() {
+        if let Some(other) = &room.config {
+            config.check_against(other).map_err(config_err)?;
+        }
+    }
+
+    let id = insert(
+        &mut rooms,
+        &config.name,
+        config.cpus().map(CPUId::from).collect(),
+        config.irqs.clone(),
+    )?;
+    let room = rooms.get_mut(&id).unwrap();
+    if let Err(e) = map_config(room, &config).and_then(|_| register_virtio(room, &config)) {
+        rooms.remove(&id);
+        switch::prune();
+        return Err(e);
+    }
+
+    // The root room loses the memory and devices of the new room, or the room is undone.
+    let taken: Vec<Range<usize>> = config
+        .memory_regions
+        .iter()
+        .filter(|r| {
+            !r.flags
+                .intersects(MemFlags::LOADABLE | MemFlags::ROOT_SHARED)
+        })
+        .map(|r| (r.phys_start, r.phys_end()))
+        .chain(
+            (0..config.devices.len())
+                .filter(|&i| config.devices[i].kind == DeviceKind::Mmio)
+                .map(|i| config.device_window(i)),
+        )
+        .chain(config.console_window())
+        .map(|(start, end)| start as usize..end as usize)
+        .collect();
+    let root = rooms
+        .get_mut(&ROOT_ROOM)
+        .ok_or_else(|| hv_err!(ENOENT, "no root room"))?;
+    for (i, range) in taken.iter().enumerate() {
+        if let Err(e) = root.space.unmap(range.start, range.len()) {
+            restore_root(root, &taken[..=i]);
+            rooms.remove(&id);
+            switch::prune();
+            return Err(e);
+        }
+    }
+
+    let room = rooms.get_mut(&id).unwrap();
+    room.entry = config.entry as _;
+    room.dtb = config.dtb as _;
+    let irqs = config.irqs.clone();
+    room.config = Some(config);
+    drop(rooms);
+
+    // And its interrupts.
+    for irq in irqs {
+        gic::unforward_irq(irq);
+    }
+    Ok(id)
+}
+
+/// Map back what the root room lost of `ranges`, when a room could not be created. An unmap
+/// may have failed midway, so only the pages missing are mapped.
+fn restore_root(root: &mut Room, ranges: &[Range<usize>]) {
+    let ram = mem::guest_ram().map(|r| {
+        (
+            r.start.as_usize()..r.end.as_usize(),
+            AccessSetting::Read | AccessSetting::Write | AccessSetting::Execute,
+            CacheSetting::Normal,
+        )
+    });
+    let devices = root.devices.iter().map(|r| {
+        (
+            r.clone(),
+            AccessSetting::Read | AccessSetting::Write,
+            CacheSetting::Device,
+        )
+    });
+    for (owned, access, cache) in ram.chain(devices) {
+        for range in ranges {
+            let start = range.start.max(owned.start);
+            let end = range.end.min(owned.end);
+            if start >= end {
+                continue;
+            }
+            if let Err(e) = root
+                .space
+                .map_holes(start, start, end - start, access, cache)
+            {
+                error!("root room lost [{:#x}, {:#x}): {:?}", start, end, e);
+            }
+        }
+    }
+}
+
+fn map_config(room: &mut Room, config: &CellConfig) -> HvResult {
+    // Disks are only read by the hypervisor.
+    for region in config
+        .memory_regions
+        .iter()
+        .filter(|r| !r.flags.contains(MemFlags::DISK))
+    {
+        let mut access = AccessSetting::empty();
+        if region.flags.contains(MemFlags::READ) {
+            access |= AccessSetting::Read;
+        }
+        if region.flags.contains(MemFlags::WRITE) {
+            access |= AccessSetting::Write;
+        }
+        if region.flags.contains(MemFlags::EXECUTE) {
+            access |= AccessSetting::Execute;
+        }
+        let cache = match region.flags.contains(MemFlags::IO) {
+            true => CacheSetting::Device,
+            false => CacheSetting::Normal,
+        };
+        room.space.map(
+            region.virt_start as _,
+            region.phys_start as _,
+            region.size as _,
+            access,
+            cache,
+        )?;
+    }
+
+    let mmio = (0..config.devices.len())
+        .filter(|&i| config.devices[i].kind == DeviceKind::Mmio)
+        .map(|i| config.device_window(i))
+        .chain(config.console_window());
+    for (start, end) in mmio {
+        room.space.map(
+            start as _,
+            start as _,
+            (end - start) as _,
+            AccessSetting::Read | AccessSetting::Write,
+            CacheSetting::Device,
+        )?;
+    }
+    Ok(())
+}
+
+/// The virtio-mmio windows of the room: emulated here, or served by the backend in the root
+/// room.
+fn register_virtio(room: &mut Room, config: &CellConfig) -> HvResult {
+    for device in &config.devices {
+        let base = device.address as usize;
+        let handler: Arc<dyn MmioHandler> = match device.kind {
+            DeviceKind::Virtio => Arc::new(VirtioMmio {
+                room: room.id,
+                base,
+            }),
+            DeviceKind::VirtioConsole => Arc::new(MmioTransport::new(
+                room.id,
+                device.irq(),
+                Console::new(&room.name),
+            )),
+            DeviceKind::VirtioBlk => {
+                let disk = &config.memory_regions[device.region()];
+                let block = Block::new(
+                    disk.phys_start as usize..disk.phys_end() as usize,
+                    device.read_only(),
+                    &format!("{}@{:x}", room.name, base),
+                )?;
+                Arc::new(MmioTransport::new(room.id, device.irq(), block))
+            }
+            DeviceKind::VirtioNet => {
+                let net = Net::new(device.mac(), device.rate());
+                let port = Arc::new(MmioTransport::new(room.id, device.irq(), net));
+                switch::attach(&port)?;
+                port
+            }
+            _ => continue,
+        };
+        room.mmio
+            .register(base..base + device.size as usize, handler)?;
+    }
+    Ok(())
+}
+
+/// Run `f` with room `id` locked.
+pub fn with<R>(id: RoomId, f: impl FnOnce(&mut Room) -> R) -> HvResult<R> {
+    let mut rooms = ROOMS.lock();
+    let room = rooms
+        .get_mut(&id)
+        .ok_or_else(|| hv_err!(ENOENT, format!("room {} not found", id)))?;
+    Ok(f(room))
+}
+
+/// Start a created, stopped or reset room from its entry on its boot cpu. Nothing is reset in
+/// its memory, the root room loads the images of a stopped room again first.
+pub fn start(id: RoomId) -> HvResult {
+    let mut rooms = ROOMS.lock();
+    let room = rooms
+        .get(&id)
+        .ok_or_else(|| hv_err!(ENOENT, format!("room {} not found", id)))?;
+    if !matches!(
+        room.state,
+        RoomState::Created | RoomState::Stopped | RoomState::Reset
+    ) {
+        return hv_result_err!(EBUSY, format!("room {} is {:?}", room.name, room.state));
+    }
+    if room
+        .cpus
+        .iter()
+        .any(|&cpu| get_cpu_data(cpu).park_requested())
+    {
+        return hv_result_err!(EBUSY, format!("room {} is still stopping", room.name));
+    }
+
+    if let Some(config) = room.config.clone() {
+        let root = rooms
+            .get_mut(&ROOT_ROOM)
+            .ok_or_else(|| hv_err!(ENOENT, "no root room"))?;
+        unmap_loadable(root, &config)?;
+    }
+
+    let room = rooms.get_mut(&id).unwrap();
+    room.state = RoomState::Running;
+    room.cntvoff = arch::timer::counter();
+    info!("Room {} [{}] start", room.id, room.name);
+    get_cpu_data(room.cpus[0]).post(CpuRequest::Run(room.id));
+    Ok(())
+}
+
+/// Stop a running or failed room, its cpus are parked at their next exit. The root room gets
+/// the loadable regions back, to load the images again before it starts the room anew.
+pub fn stop(id: RoomId) -> HvResult {
+    halt(&mut ROOMS.lock(), id, RoomState::Stopped)
+}
+
+fn halt(rooms: &mut BTreeMap<RoomId, Room>, id: RoomId, state: RoomState) -> HvResult {
+    let room = rooms
+        .get_mut(&id)
+        .ok_or_else(|| hv_err!(ENOENT, format!("room {} not found", id)))?;
+    if !matches!(room.state, RoomState::Running | RoomState::Failed) {
+        return hv_result_err!(EINVAL, format!("room {} is {:?}", room.name, room.state));
+    }
+    room.state = state;
+    info!("Room {} [{}] stop", room.id, room.name);
+    park_cpus(room);
+
+    if let Some(config) = room.config.clone() {
+        let root = rooms
+            .get_mut(&ROOT_ROOM)
+            .ok_or_else(|| hv_err!(ENOENT, "no root room"))?;
+        map_loadable(root, &config)?;
+    }
+    Ok(())
+}
+
+/// Restart the room of this cpu for its guest `SYSTEM_RESET`: its boot cpu enters it again
+/// from its entry and dtb, the other cpus are parked. The memory is left as the guest had
+/// it, so a room booted from images the root room loaded is stopped in [`RoomState::Reset`]
+/// instead, for the root room to load them again and start it; it is told so with
+/// [`ROOM_RESET_IRQ`].
+pub fn reset(id: RoomId) -> ! {
+    let mut rooms = ROOMS.lock();
+    let loaded = rooms.get(&id).is_some_and(|room| {
+        room.config.as_ref().is_some_and(|config| {
+            config
+                .memory_regions
+                .iter()
+                .any(|r| r.flags.contains(MemFlags::LOADABLE))
+        })
+    });
+    match rooms.get_mut(&id) {
+        Some(room) if !loaded => {
+            info!("Room {} [{}] reset", room.id, room.name);
+            room.cntvoff = arch::timer::counter();
+            for (i, &cpu) in room.cpus.iter().enumerate() {
+                get_cpu_data(cpu).post(match i {
+                    0 => CpuRequest::Run(id),
+                    _ => CpuRequest::Park,
+                });
+            }
+        }
+        _ => {
+            let halted = halt(&mut rooms, id, RoomState::Reset);
+            drop(rooms);
+            if let Err(e) = halted.and_then(|_| inject_irq(ROOT_ROOM, ROOM_RESET_IRQ)) {
+                error!("Reset room {}: {:?}", id, e);
+            }
+            park()
+        }
+    }
+    drop(rooms);
+    park()
+}
+
+/// The guest of room `id` did something it cannot recover from. The root room takes the
+/// machine down with it, other rooms are marked failed and parked.
+pub fn fail(id: RoomId) -> ! {
+    if id == ROOT_ROOM {
+        error!("Root room failed");
+        arch::shutdown();
+    }
+    let _ = with(id, |room| {
+        error!("Room {} [{}] failed", room.id, room.name);
+        room.state = RoomState::Failed;
+        park_cpus(room);
+    });
+    park()
+}
+
+pub fn list() -> Vec<RoomInfo> {
+    ROOMS
+        .lock()
+        .values()
+        .map(|room| RoomInfo {
+            id: room.id as _,
+            state: room.state as _,
+            cpu_mask: room.cpus.iter().fold(0, |mask, cpu| mask | 1 << cpu.raw()),
+        })
+        .collect()
+}
+
+/// Make `intid` pending in room `id`, on the vcpu of its boot cpu.
+pub fn inject_irq(id: RoomId, intid: u32) -> HvResult {
+    let cpu = with(id, |room| match room.state {
+        RoomState::Running => Ok(room.cpus[0]),
+        state => hv_result_err!(EINVAL, format!("room {} is {:?}", room.name, state)),
+    })??;
+    vgic::inject(cpu, Virq::new(intid));
+    Ok(())
+}
+
+/// The room whose guest sees `intid`: the one it is assigned to, otherwise the root room.
+pub fn irq_owner(intid: u32) -> RoomId {
+    ROOMS
+        .lock()
+        .values()
+        .find(|room| room.id != ROOT_ROOM && room.irqs.contains(&intid))
+        .map_or(ROOT_ROOM, |room| room.id)
+}
+
+/// The root room loses access to the images it loaded into a room about to start.
+fn unmap_loadable(root: &mut Room, config: &CellConfig) -> HvResult {
+    for region in config.memory_regions.iter().filter(|r| {
+        r.flags.contains(MemFlags::LOADABLE) && !r.flags.contains(MemFlags::ROOT_SHARED)
+    }) {
+        root.space.unmap(region.phys_start as _, region.size as _)?;
+    }
+    Ok(())
+}
+
+/// The root room may load the images of a stopped room again.
+fn map_loadable(root: &mut Room, config: &CellConfig) -> HvResult {
+    for region in config.memory_regions.iter().filter(|r| {
+        r.flags.contains(MemFlags::LOADABLE) && !r.flags.contains(MemFlags::ROOT_SHARED)
+    }) {
+        root.space.map(
+            region.phys_start as _,
+            region.phys_start as _,
+            region.size as _,
+            AccessSetting::Read | AccessSetting::Write | AccessSetting::Execute,
+            CacheSetting::Normal,
+        )?;
+    }
+    Ok(())
+}
+
+fn park_cpus(room: &Room) {
+    for &cpu in &room.cpus {
+        if cpu != cpu_data().id {
+            get_cpu_data(cpu).post(CpuRequest::Park);
+        }
+    }
+}
+
+/// Drop the vcpu of this cpu and wait for new work.
+pub fn park() -> ! {
+    if let Some(vcpu) = cpu_data().vcpu() {
+        for irq in with(vcpu.room, |room| room.irqs.clone()).unwrap_or_default() {
+            gic::unforward_irq(irq);
+        }
+    }
+    cpu_off()
+}
+
+/// Drop the vcpu of this cpu only, the room goes on with its others (guest `CPU_OFF`).
+pub fn cpu_off() -> ! {
+    let cpu = cpu_data();
+    vgic::reset();
+    arch::timer::stop_virtual();
+    gic::unforward_irq(arch::timer::VIRT_TIMER_IRQ);
+    cpu.clear_vcpu();
+    info!("CPU {} parked", cpu.id);
+    idle()
+}
+
+/// Wait for a room to run on this cpu, serving the interrupts of the hypervisor meanwhile.
+/// Requests come with a kick, which ends the wait.
+pub fn idle() -> ! {
+    let cpu = cpu_data();
+    loop {
+        match cpu.take_request() {
+            Some(CpuRequest::Run(id)) => {
+                if let Err(e) = enter(id, None) {
+                    error!("Enter room {} failed: {:?}", id, e);
+                    let _ = with(id, |room| room.state = RoomState::Failed);
+                }
+            }
+            Some(CpuRequest::CpuOn {
+                room,
+                entry,
+                context,
+            }) => {
+                if let Err(e) = enter(room, Some((entry, context))) {
+                    error!("CPU_ON in room {} failed: {:?}", room, e);
+                }
+            }
+            Some(CpuRequest::Park) => {}
+            None => arch::wait_for_irq(),
+        }
+    }
+}
+
+/// Run the vcpu of room `id` for this cpu, from the room entry with the dtb or from the
+/// `(entry, x0)` of a `CPU_ON`.
+fn enter(id: RoomId, start: Option<(usize, usize)>) -> HvResult {
+    let this = cpu_data().id;
+    let (vcpu_id, entry, dtb, cntvoff) = with(id, |room| {
+        room.space.activate();
+        let vcpu_id = room.cpus.iter().position(|&cpu| cpu == this).unwrap_or(0);
+        (vcpu_id, room.entry, room.dtb, room.cntvoff)
+    })?;
+    let (entry, dtb) = start.unwrap_or((entry, dtb));
+    if entry == 0 {
+        warn!("room {} has no entry", id);
+        return hv_result_err!(ENOEXEC);
+    }
+
+    // The guest enables its interrupts through the emulated distributor.
+    vgic::reset();
+
+    let vcpu = cpu_data().set_vcpu(VCpu::new(vcpu_id, id));
+    vcpu.reset(entry, dtb);
+    vcpu.cntvoff = cntvoff;
+    info!("CPU {} enter room {} at {:#x}", cpu_data().id, id, entry);
+    vcpu.run()
+}
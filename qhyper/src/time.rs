@@ -1,5 +0,0 @@
-use core::time::Duration;
-
-pub fn since_boot() -> Duration {
-    Duration::from_secs(0)
-}
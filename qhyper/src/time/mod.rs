@@ -0,0 +1,151 @@
+//! Time from the generic timer: a monotonic clock counting from the entry of the hypervisor,
+//! and the wall clock when the fdt describes a PL031 RTC.
+
+use core::{
+    hint::spin_loop,
+    ops::{Add, Sub},
+    time::Duration,
+};
+
+use log::info;
+use memory_addr::{pa_range, PhysAddrRange};
+use page_table_generic::{AccessSetting, CacheSetting};
+
+use crate::{
+    arch,
+    mem::{get_fdt, once::OnceStatic, space::Space},
+};
+
+pub mod timer;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+const COMPATIBLE_PL031: [&str; 1] = ["arm,pl031"];
+/// PL031 data register, seconds since the epoch.
+const RTCDR: usize = 0x000;
+
+/// The counter at the entry of the hypervisor.
+static BOOT_COUNTER: OnceStatic<u64> = OnceStatic::new(0);
+static RTC: OnceStatic<Option<PhysAddrRange>> = OnceStatic::new(None);
+/// The wall clock read from the RTC and when it was read.
+static WALL_CLOCK: OnceStatic<Option<(Duration, Instant)>> = OnceStatic::new(None);
+
+/// A point of the monotonic clock, in counter ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(arch::timer::counter())
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / arch::timer::frequency().max(1) as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * arch::timer::frequency() as u128 / NANOS_PER_SEC;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Called by the boot code with the counter read at entry, before anything else is set up.
+pub extern "C" fn record_boot(counter: u64) {
+    unsafe { BOOT_COUNTER.set(counter) };
+}
+
+pub fn boot_instant() -> Instant {
+    Instant(*BOOT_COUNTER)
+}
+
+pub fn since_boot() -> Duration {
+    boot_instant().elapsed()
+}
+
+/// Spin for `duration`.
+pub fn delay(duration: Duration) {
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        spin_loop();
+    }
+}
+
+/// Find the RTC in the fdt, must run before the hypervisor page table is built.
+pub fn probe() {
+    let Some(fdt) = get_fdt() else {
+        return;
+    };
+    let rtc = fdt
+        .find_compatible(&COMPATIBLE_PL031)
+        .next()
+        .and_then(|node| node.reg()?.next())
+        .map(|reg| {
+            let start = reg.address as usize;
+            pa_range!(start..start + reg.size.unwrap_or(0x1000))
+        });
+    unsafe { RTC.set(rtc) };
+}
+
+/// The RTC frame, identity mapped.
+pub fn space() -> Option<Space> {
+    RTC.map(|phys| Space {
+        name: "rtc",
+        phys,
+        offset: 0,
+        access: AccessSetting::Read | AccessSetting::Write,
+        cache: CacheSetting::Device,
+    })
+}
+
+/// Read the wall clock once, later readings follow the counter.
+pub fn init() {
+    let Some(rtc) = *RTC else {
+        return;
+    };
+    let secs = unsafe { ((rtc.start.as_usize() + RTCDR) as *const u32).read_volatile() };
+    let wall = Duration::from_secs(secs as u64);
+    unsafe { WALL_CLOCK.set(Some((wall, Instant::now()))) };
+    info!("wall clock: {}s since the epoch", secs);
+}
+
+/// Time since the Unix epoch, `None` without RTC.
+pub fn wall_time() -> Option<Duration> {
+    WALL_CLOCK.map(|(wall, at)| wall + at.elapsed())
+}
@@ -0,0 +1,114 @@
+//! One-shot timers on the EL2 physical timer. Each cpu keeps its timers sorted by deadline
+//! and programs the earliest one, callbacks run on that cpu from the timer interrupt.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+use log::warn;
+
+use crate::{
+    arch::{gic, timer},
+    percpu::{cpu_data, get_cpu_data, CPUId},
+};
+
+use super::Instant;
+
+pub type TimerCallback = Box<dyn FnOnce() + Send>;
+
+/// A pending timer, to [`cancel`] it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    cpu: CPUId,
+    seq: u64,
+}
+
+pub struct TimerEvent {
+    seq: u64,
+    deadline: Instant,
+    callback: TimerCallback,
+}
+
+impl fmt::Debug for TimerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimerEvent")
+            .field("seq", &self.seq)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    if let Err(e) = gic::register_handler(timer::HYP_TIMER_IRQ, handle_irq) {
+        warn!("hypervisor timer: {:?}", e);
+    }
+    init_cpu();
+}
+
+/// Enable the timer interrupt of the current cpu, with nothing scheduled.
+pub fn init_cpu() {
+    timer::init_cpu();
+    gic::enable_irq(timer::HYP_TIMER_IRQ);
+}
+
+/// Run `callback` on the current cpu once `deadline` passed.
+pub fn add_timer(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let cpu = cpu_data();
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut timers = cpu.timers.lock();
+        let at = timers.partition_point(|t| t.deadline <= deadline);
+        timers.insert(
+            at,
+            TimerEvent {
+                seq,
+                deadline,
+                callback: Box::new(callback),
+            },
+        );
+    }
+    program();
+    TimerId { cpu: cpu.id, seq }
+}
+
+/// Drop timer `id` if it has not fired yet, returns whether it was pending.
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = get_cpu_data(id.cpu).timers.lock();
+    let Some(at) = timers.iter().position(|t| t.seq == id.seq) else {
+        return false;
+    };
+    timers.remove(at);
+    drop(timers);
+    // A remote cpu finds nothing due at its interrupt and programs its next timer.
+    if id.cpu == cpu_data().id {
+        program();
+    }
+    true
+}
+
+/// Arm the timer of the current cpu for its earliest deadline.
+fn program() {
+    match cpu_data().timers.lock().first() {
+        Some(first) => timer::set_hyp_deadline(first.deadline.ticks()),
+        None => timer::stop_hyp(),
+    }
+}
+
+/// Run the callbacks that are due, without the queue locked so that they can add timers.
+fn handle_irq(_intid: u32) {
+    loop {
+        let event = {
+            let mut timers = cpu_data().timers.lock();
+            match timers.first() {
+                Some(first) if first.deadline <= Instant::now() => timers.remove(0),
+                _ => break,
+            }
+        };
+        (event.callback)();
+    }
+    program();
+}
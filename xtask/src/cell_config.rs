@@ -0,0 +1,589 @@
+//! `cargo xtask cell-config`: cell configs as TOML, compiled to the blobs qhyper loads.
+
+use std::{fmt::Write as _, fs, path::Path};
+
+use qhyper_config::{
+    CellConfig, Console, ConsoleKind, Device, DeviceKind, MemFlags, MemoryRegion, SmcOwners,
+    DEVICE_IRQ_MASK, DEVICE_RATE_MASK, DEVICE_RATE_SHIFT, DEVICE_READ_ONLY, DEVICE_REGION_MASK,
+    DEVICE_REGION_SHIFT,
+};
+use serde::Deserialize;
+
+const USAGE: &str = "usage: cargo xtask cell-config <command>
+
+commands:
+    compile <cell.toml> [-o <cell.bin>]   write the binary config, next to the input by default
+    decompile <cell.bin>                  print a binary config as TOML
+    validate <cell>...                    check each config and that they do not conflict
+    map <cell>...                         print the memory map of all the cells
+
+A <cell> is either a TOML file or a binary config.";
+
+const MEM_FLAGS: [(&str, MemFlags); 7] = [
+    ("read", MemFlags::READ),
+    ("write", MemFlags::WRITE),
+    ("execute", MemFlags::EXECUTE),
+    ("io", MemFlags::IO),
+    ("loadable", MemFlags::LOADABLE),
+    ("root-shared", MemFlags::ROOT_SHARED),
+    ("disk", MemFlags::DISK),
+];
+
+const SMC_OWNERS: [(&str, SmcOwners); 7] = [
+    ("cpu", SmcOwners::CPU),
+    ("sip", SmcOwners::SIP),
+    ("oem", SmcOwners::OEM),
+    ("standard-secure", SmcOwners::STANDARD_SECURE),
+    ("vendor-el3", SmcOwners::VENDOR_EL3),
+    ("trusted-app", SmcOwners::TRUSTED_APP),
+    ("trusted-os", SmcOwners::TRUSTED_OS),
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CellToml {
+    name: String,
+    cpus: Vec<usize>,
+    entry: u64,
+    #[serde(default)]
+    dtb: u64,
+    #[serde(default)]
+    irqs: Vec<u32>,
+    console: Option<ConsoleToml>,
+    /// SMCCC owners forwarded to the firmware.
+    #[serde(default)]
+    smc: Vec<String>,
+    #[serde(default)]
+    memory: Vec<MemoryToml>,
+    #[serde(default)]
+    device: Vec<DeviceToml>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConsoleToml {
+    kind: String,
+    address: u64,
+    size: u64,
+    #[serde(default)]
+    flags: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemoryToml {
+    phys: u64,
+    /// Defaults to `phys`.
+    virt: Option<u64>,
+    size: u64,
+    flags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceToml {
+    kind: String,
+    address: u64,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    flags: u32,
+    /// SPI of an emulated device, merged into `flags`.
+    irq: Option<u32>,
+    /// Index of the memory region backing a disk.
+    disk: Option<usize>,
+    #[serde(default, rename = "read-only")]
+    read_only: bool,
+    /// MAC address of a network card, as `52:54:00:12:34:56`.
+    mac: Option<String>,
+    /// Rate limit of a network card in Mbit/s.
+    rate: Option<u32>,
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    match (args.first().map(String::as_str), &args[1.min(args.len())..]) {
+        (Some("compile"), [input]) => {
+            compile(Path::new(input), &Path::new(input).with_extension("bin"))
+        }
+        (Some("compile"), [input, o, output]) if o == "-o" => {
+            compile(Path::new(input), Path::new(output))
+        }
+        (Some("decompile"), [input]) => {
+            print!("{}", to_toml(&load(Path::new(input))?));
+            Ok(())
+        }
+        (Some("validate"), inputs) if !inputs.is_empty() => {
+            let cells = load_all(inputs)?;
+            for (path, cell) in inputs.iter().zip(&cells) {
+                println!("{}: {} ok", path, cell.name);
+            }
+            Ok(())
+        }
+        (Some("map"), inputs) if !inputs.is_empty() => {
+            print!("{}", memory_map(&load_all(inputs)?));
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn compile(input: &Path, output: &Path) -> Result<(), String> {
+    let cell = load(input)?;
+    let blob = cell
+        .to_bytes()
+        .map_err(|e| format!("{}: {}", input.display(), e))?;
+    fs::write(output, &blob).map_err(|e| format!("{}: {}", output.display(), e))?;
+    println!(
+        "{} -> {} ({} bytes)",
+        input.display(),
+        output.display(),
+        blob.len()
+    );
+    Ok(())
+}
+
+/// Read a TOML or binary config, by content.
+fn load(path: &Path) -> Result<CellConfig, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let result = if data.starts_with(&qhyper_config::MAGIC) {
+        CellConfig::parse(&data).map_err(|e| e.to_string())
+    } else {
+        String::from_utf8(data)
+            .map_err(|e| e.to_string())
+            .and_then(|text| from_toml(&text))
+    };
+    result.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Load every config and check them against each other, as qhyper does when creating them
+/// one after the other.
+fn load_all(paths: &[String]) -> Result<Vec<CellConfig>, String> {
+    let mut cells: Vec<CellConfig> = Vec::new();
+    for path in paths {
+        let cell = load(Path::new(path))?;
+        for other in &cells {
+            cell.check_against(other)
+                .map_err(|e| format!("{}: {} (against {})", path, e, other.name))?;
+        }
+        cells.push(cell);
+    }
+    Ok(cells)
+}
+
+fn from_toml(text: &str) -> Result<CellConfig, String> {
+    let cell: CellToml = toml::from_str(text).map_err(|e| e.to_string())?;
+
+    let mut cpu_set = 0u64;
+    for cpu in cell.cpus {
+        if cpu >= 64 {
+            return Err(format!("cpu {} out of range", cpu));
+        }
+        cpu_set |= 1 << cpu;
+    }
+
+    let console = match cell.console {
+        Some(c) => Console {
+            kind: match c.kind.as_str() {
+                "none" => ConsoleKind::None,
+                "pl011" => ConsoleKind::Pl011,
+                "aux-mini" => ConsoleKind::AuxMini,
+                kind => return Err(format!("unknown console kind {}", kind)),
+            },
+            flags: c.flags,
+            address: c.address,
+            size: c.size,
+        },
+        None => Console::default(),
+    };
+
+    let mut smc_owners = SmcOwners::empty();
+    for name in &cell.smc {
+        smc_owners |= SMC_OWNERS
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, o)| *o)
+            .ok_or_else(|| format!("unknown smc owner {}", name))?;
+    }
+
+    let mut memory_regions = Vec::new();
+    for m in cell.memory {
+        let mut flags = MemFlags::empty();
+        for name in &m.flags {
+            flags |= MEM_FLAGS
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, f)| *f)
+                .ok_or_else(|| format!("unknown memory flag {}", name))?;
+        }
+        memory_regions.push(MemoryRegion {
+            phys_start: m.phys,
+            virt_start: m.virt.unwrap_or(m.phys),
+            size: m.size,
+            flags,
+        });
+    }
+
+    let mut devices = Vec::new();
+    for d in cell.device {
+        let kind = match d.kind.as_str() {
+            "mmio" => DeviceKind::Mmio,
+            "pci" => DeviceKind::Pci,
+            "virtio" => DeviceKind::Virtio,
+            "virtio-console" => DeviceKind::VirtioConsole,
+            "virtio-blk" => DeviceKind::VirtioBlk,
+            "virtio-net" => DeviceKind::VirtioNet,
+            kind => return Err(format!("unknown device kind {}", kind)),
+        };
+        let irq = match d.irq {
+            Some(irq) if !kind.is_emulated() => {
+                return Err(format!("{} device can not raise irq {}", d.kind, irq))
+            }
+            Some(irq) if irq & !DEVICE_IRQ_MASK != 0 => {
+                return Err(format!("irq {} out of range", irq))
+            }
+            irq => irq.unwrap_or(0),
+        };
+        let mut flags = d.flags | irq;
+        if kind == DeviceKind::VirtioBlk {
+            let index = d.disk.ok_or("virtio-blk device without disk region")?;
+            if index > (DEVICE_REGION_MASK >> DEVICE_REGION_SHIFT) as usize {
+                return Err(format!("disk region {} out of range", index));
+            }
+            flags |= (index as u32) << DEVICE_REGION_SHIFT;
+            if d.read_only {
+                flags |= DEVICE_READ_ONLY;
+            }
+        } else if d.disk.is_some() || d.read_only {
+            return Err(format!("{} device has no disk", d.kind));
+        }
+        let mut param = 0;
+        if kind == DeviceKind::VirtioNet {
+            let mac = d.mac.as_deref().ok_or("virtio-net device without mac")?;
+            param = parse_mac(mac)?;
+            let rate = d.rate.unwrap_or(0);
+            if rate > DEVICE_RATE_MASK >> DEVICE_RATE_SHIFT {
+                return Err(format!("rate {} out of range", rate));
+            }
+            flags |= rate << DEVICE_RATE_SHIFT;
+        } else if d.mac.is_some() || d.rate.is_some() {
+            return Err(format!("{} device is no network card", d.kind));
+        }
+        devices.push(Device {
+            kind,
+            flags,
+            address: d.address,
+            size: d.size,
+            param,
+        });
+    }
+
+    let config = CellConfig {
+        name: cell.name,
+        cpu_set,
+        entry: cell.entry,
+        dtb: cell.dtb,
+        console,
+        smc_owners,
+        memory_regions,
+        irqs: cell.irqs,
+        devices,
+    };
+    config.validate().map_err(|e| e.to_string())?;
+    Ok(config)
+}
+
+fn parse_mac(mac: &str) -> Result<u64, String> {
+    let bytes = mac
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .filter(|b| b.len() == 6)
+        .ok_or_else(|| format!("bad mac {}", mac))?;
+    Ok(bytes.iter().fold(0, |v, &b| v << 8 | b as u64))
+}
+
+fn flag_names(flags: MemFlags) -> Vec<&'static str> {
+    MEM_FLAGS
+        .iter()
+        .filter(|(_, f)| flags.contains(*f))
+        .map(|(n, _)| *n)
+        .collect()
+}
+
+fn to_toml(cell: &CellConfig) -> String {
+    let mut s = String::new();
+    let _ = writeln!(s, "name = {:?}", cell.name);
+    let _ = writeln!(s, "cpus = {:?}", cell.cpus().collect::<Vec<_>>());
+    let _ = writeln!(s, "entry = {:#x}", cell.entry);
+    let _ = writeln!(s, "dtb = {:#x}", cell.dtb);
+    let _ = writeln!(s, "irqs = {:?}", cell.irqs);
+    let smc = SMC_OWNERS
+        .iter()
+        .filter(|(_, o)| cell.smc_owners.contains(*o))
+        .map(|(n, _)| *n)
+        .collect::<Vec<_>>();
+    if !smc.is_empty() {
+        let _ = writeln!(s, "smc = {:?}", smc);
+    }
+
+    let c = &cell.console;
+    if c.kind != ConsoleKind::None {
+        let kind = match c.kind {
+            ConsoleKind::None => "none",
+            ConsoleKind::Pl011 => "pl011",
+            ConsoleKind::AuxMini => "aux-mini",
+        };
+        let _ = writeln!(s, "\n[console]\nkind = {:?}", kind);
+        let _ = writeln!(s, "address = {:#x}\nsize = {:#x}", c.address, c.size);
+        if c.flags != 0 {
+            let _ = writeln!(s, "flags = {:#x}", c.flags);
+        }
+    }
+
+    for m in &cell.memory_regions {
+        let _ = writeln!(s, "\n[[memory]]");
+        let _ = writeln!(s, "phys = {:#x}", m.phys_start);
+        let _ = writeln!(s, "virt = {:#x}", m.virt_start);
+        let _ = writeln!(s, "size = {:#x}", m.size);
+        let _ = writeln!(s, "flags = {:?}", flag_names(m.flags));
+    }
+
+    for d in &cell.devices {
+        let kind = match d.kind {
+            DeviceKind::Mmio => "mmio",
+            DeviceKind::Pci => "pci",
+            DeviceKind::Virtio => "virtio",
+            DeviceKind::VirtioConsole => "virtio-console",
+            DeviceKind::VirtioBlk => "virtio-blk",
+            DeviceKind::VirtioNet => "virtio-net",
+        };
+        let _ = writeln!(s, "\n[[device]]\nkind = {:?}", kind);
+        let _ = writeln!(s, "address = {:#x}\nsize = {:#x}", d.address, d.size);
+        let mut flags = d.flags;
+        if d.kind.is_emulated() {
+            let _ = writeln!(s, "irq = {}", d.irq());
+            flags &= !DEVICE_IRQ_MASK;
+        }
+        if d.kind == DeviceKind::VirtioBlk {
+            let _ = writeln!(s, "disk = {}", d.region());
+            if d.read_only() {
+                let _ = writeln!(s, "read-only = true");
+            }
+            flags &= !(DEVICE_REGION_MASK | DEVICE_READ_ONLY);
+        }
+        if d.kind == DeviceKind::VirtioNet {
+            let mac = d.mac().map(|b| format!("{:02x}", b)).join(":");
+            let _ = writeln!(s, "mac = {:?}", mac);
+            if d.rate() != 0 {
+                let _ = writeln!(s, "rate = {}", d.rate());
+            }
+            flags &= !DEVICE_RATE_MASK;
+        }
+        if flags != 0 {
+            let _ = writeln!(s, "flags = {:#x}", flags);
+        }
+    }
+    s
+}
+
+/// Every memory region, console and MMIO device of the cells, by physical address.
+fn memory_map(cells: &[CellConfig]) -> String {
+    let mut rows = Vec::new();
+    for cell in cells {
+        for m in &cell.memory_regions {
+            rows.push((
+                m.phys_start,
+                m.phys_end(),
+                format!("{:#x}", m.virt_start),
+                flag_names(m.flags).join(","),
+                &cell.name,
+            ));
+        }
+        let c = &cell.console;
+        if c.kind != ConsoleKind::None {
+            rows.push((
+                c.address,
+                c.address + c.size,
+                format!("{:#x}", c.address),
+                "console".to_string(),
+                &cell.name,
+            ));
+        }
+        for d in &cell.devices {
+            let kind = match d.kind {
+                DeviceKind::Mmio => "mmio",
+                DeviceKind::Virtio => "virtio",
+                DeviceKind::VirtioConsole => "virtio-console",
+                DeviceKind::VirtioBlk => "virtio-blk",
+                DeviceKind::VirtioNet => "virtio-net",
+                DeviceKind::Pci => continue,
+            };
+            rows.push((
+                d.address,
+                d.address + d.size,
+                format!("{:#x}", d.address),
+                kind.to_string(),
+                &cell.name,
+            ));
+        }
+    }
+    rows.sort_by_key(|r| (r.0, r.1));
+
+    let mut s = String::new();
+    let _ = writeln!(
+        s,
+        "{:<32} {:<14} {:<12} {:<32} cell",
+        "physical", "guest", "size", "flags"
+    );
+    for (start, end, virt, flags, name) in rows {
+        let _ = writeln!(
+            s,
+            "[{:#014x}, {:#014x}) {:<14} {:<12} {:<32} {}",
+            start,
+            end,
+            virt,
+            format!("{:#x}", end - start),
+            flags,
+            name
+        );
+    }
+    for cell in cells {
+        let _ = writeln!(
+            s,
+            "{}: cpus {:?}, irqs {:?}",
+            cell.name,
+            cell.cpus().collect::<Vec<_>>(),
+            cell.irqs
+        );
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use qhyper_config::ConfigError;
+
+    use super::*;
+
+    const CELL: &str = r#"
+name = "rtos"
+cpus = [2, 3]
+entry = 0x50000000
+dtb = 0x50f00000
+irqs = [80, 81, 82]
+smc = ["sip", "trusted-os"]
+
+[console]
+kind = "pl011"
+address = 0x9000000
+size = 0x1000
+
+[[memory]]
+phys = 0x50000000
+size = 0x1000000
+flags = ["read", "write", "execute", "loadable"]
+
+[[memory]]
+phys = 0x9100000
+virt = 0x20000000
+size = 0x1000
+flags = ["read", "write", "io"]
+
+[[memory]]
+phys = 0x52000000
+size = 0x800000
+flags = ["disk"]
+
+[[device]]
+kind = "mmio"
+address = 0x9200000
+size = 0x100
+
+[[device]]
+kind = "virtio-console"
+address = 0xa000000
+size = 0x200
+irq = 80
+
+[[device]]
+kind = "virtio-blk"
+address = 0xa000200
+size = 0x200
+irq = 81
+disk = 2
+read-only = true
+
+[[device]]
+kind = "virtio-net"
+address = 0xa000400
+size = 0x200
+irq = 82
+mac = "52:54:00:12:34:56"
+rate = 100
+"#;
+
+    #[test]
+    fn toml_round_trip() {
+        let cell = from_toml(CELL).unwrap();
+        assert_eq!(cell.cpu_set, 0b1100);
+        assert_eq!(cell.smc_owners, SmcOwners::SIP | SmcOwners::TRUSTED_OS);
+        assert_eq!(cell.memory_regions[0].virt_start, 0x5000_0000);
+        assert_eq!(cell.devices[2].region(), 2);
+        assert!(cell.devices[2].read_only());
+        assert_eq!(cell.devices[3].mac(), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        assert_eq!(cell.devices[3].rate(), 100);
+        assert_eq!(from_toml(&to_toml(&cell)).unwrap(), cell);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let cell = from_toml(CELL).unwrap();
+        let blob = cell.to_bytes().unwrap();
+        let parsed = CellConfig::parse(&blob).unwrap();
+        assert_eq!(to_toml(&parsed), to_toml(&cell));
+    }
+
+    fn error_of(from: &str, to: &str) -> String {
+        assert!(CELL.contains(from));
+        from_toml(&CELL.replacen(from, to, 1)).unwrap_err()
+    }
+
+    #[test]
+    fn bad_toml() {
+        assert!(error_of("rate = 100", "speed = 100").contains("speed"));
+        assert_eq!(
+            error_of("cpus = [2, 3]", "cpus = [64]"),
+            "cpu 64 out of range"
+        );
+        assert_eq!(
+            error_of("\"disk\"]", "\"floppy\"]"),
+            "unknown memory flag floppy"
+        );
+        assert_eq!(error_of("\"sip\"", "\"bank\""), "unknown smc owner bank");
+        assert_eq!(
+            error_of("size = 0x100\n", "size = 0x100\nirq = 83\n"),
+            "mmio device can not raise irq 83"
+        );
+        assert_eq!(
+            error_of("disk = 2\n", ""),
+            "virtio-blk device without disk region"
+        );
+        assert_eq!(
+            error_of("52:54:00:12:34:56", "52:54:00:12:34"),
+            "bad mac 52:54:00:12:34"
+        );
+    }
+
+    #[test]
+    fn invalid_cell() {
+        assert_eq!(
+            error_of("phys = 0x52000000", "phys = 0x50800000"),
+            ConfigError::Overlap(0, 2).to_string()
+        );
+        assert_eq!(
+            error_of("irq = 80", "irq = 90"),
+            ConfigError::DeviceIrq(1).to_string()
+        );
+    }
+}
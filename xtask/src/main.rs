@@ -1,7 +1,24 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
+#[cfg(not(target_os = "none"))]
+mod cell_config;
+
 #[cfg(not(target_os = "none"))]
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("cell-config") => cell_config::run(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
+
+#[cfg(not(target_os = "none"))]
+const USAGE: &str = "usage: cargo xtask <command>
+
+commands:
+    cell-config    compile, decompile and check cell configs";